@@ -18,7 +18,7 @@ use tokio::sync::broadcast::{Receiver, Sender};
 use tui_textarea::TextArea;
 use uuid::Uuid;
 use yapnet_client::{Client, ClientAction};
-use yapnet_core::prelude::ChatSent;
+use yapnet_core::prelude::{ChatSent, MessageData};
 
 use crate::ui::SubListState;
 
@@ -175,8 +175,8 @@ impl<'a> App<'a> {
                             format!(
                                 "> {}({}), {}",
                                 n,
-                                p.role,
-                                match p.connected {
+                                p.role.as_deref().unwrap_or("?"),
+                                match p.online {
                                     true => "online",
                                     false => "offline",
                                 }
@@ -203,6 +203,75 @@ impl<'a> App<'a> {
                     self.submit_uimessage(UIMessage::err("Not connected to server"))
                 }
             }
+            AppCommand::Vote(player_id) => {
+                if let Some(client) = &mut self.client {
+                    let res = client
+                        .send_message(
+                            yapnet_core::prelude::SubmitVote {
+                                player_id,
+                                chat_id: self.current_chat.clone(),
+                            }
+                            .into(),
+                        )
+                        .await;
+                    if let Err(e) = res {
+                        self.submit_uimessage(UIMessage::err(&format!("Client: {:?}", e)))
+                    }
+                } else {
+                    self.submit_uimessage(UIMessage::sys("Not connected"))
+                }
+            }
+            AppCommand::Actions => {
+                if let Some(client) = &self.client {
+                    let list = client
+                        .me()
+                        .map(|p| p.actions.as_slice())
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|a| format!("> {} {:?}", a.id, a.args))
+                        .collect::<Vec<String>>()
+                        .join("\n");
+                    self.submit_uimessage(UIMessage::sys(&format!("Available actions:\n{}", list)))
+                } else {
+                    self.submit_uimessage(UIMessage::sys("Not connected"))
+                }
+            }
+            AppCommand::Act(action_id, args) => {
+                if let Some(client) = &mut self.client {
+                    let res = client
+                        .send_message(yapnet_core::prelude::SubmitAction { action_id, args }.into())
+                        .await;
+                    if let Err(e) = res {
+                        self.submit_uimessage(UIMessage::err(&format!("Client: {:?}", e)))
+                    }
+                } else {
+                    self.submit_uimessage(UIMessage::sys("Not connected"))
+                }
+            }
+            AppCommand::Rewind(seq) => {
+                if let Some(client) = &mut self.client {
+                    let res = client
+                        .send_message(yapnet_core::prelude::Rewind { seq }.into())
+                        .await;
+                    if let Err(e) = res {
+                        self.submit_uimessage(UIMessage::err(&format!("Client: {:?}", e)))
+                    }
+                } else {
+                    self.submit_uimessage(UIMessage::sys("Not connected"))
+                }
+            }
+            AppCommand::Reload => {
+                if let Some(client) = &mut self.client {
+                    let res = client
+                        .send_message(yapnet_core::prelude::Reload {}.into())
+                        .await;
+                    if let Err(e) = res {
+                        self.submit_uimessage(UIMessage::err(&format!("Client: {:?}", e)))
+                    }
+                } else {
+                    self.submit_uimessage(UIMessage::sys("Not connected"))
+                }
+            }
             AppCommand::Error(err) => self.submit_uimessage(UIMessage::sys(&err)),
         }
     }
@@ -265,6 +334,65 @@ impl<'a> App<'a> {
                     self.submit_uimessage(a);
                 }
             }
+            ClientAction::Vote(e) => {
+                let text = match &self.client.as_ref().unwrap().state.messages.get(*e).unwrap().data {
+                    MessageData::BodyVoteStart(v) => {
+                        format!("Vote started in {}: {} (ends {})", v.chat_id, v.subject, v.end_time)
+                    }
+                    MessageData::BodyVoteSubmission(v) => {
+                        format!("{} votes for {} in {}", v.voter, v.target, v.chat_id)
+                    }
+                    MessageData::BodyVoteResult(v) => match &v.target {
+                        Some(target) => format!("Vote in {} ended, {} was chosen", v.chat_id, target),
+                        None => format!("Vote in {} ended without a result", v.chat_id),
+                    },
+                    _ => return,
+                };
+                self.submit_uimessage(UIMessage::sys(&text));
+            }
+            ClientAction::ActionsChanged => {
+                self.submit_uimessage(UIMessage::sys("Your available actions changed, see !actions"))
+            }
+            ClientAction::ActionResult(action_id, success, reason) => {
+                let text = match success {
+                    true => format!("Action {} accepted {}", action_id, reason),
+                    false => format!("Action {} rejected: {}", action_id, reason),
+                };
+                self.submit_uimessage(UIMessage::sys(&text));
+            }
+            ClientAction::ActionSelected(action_id) => {
+                let text = match action_id {
+                    Some(action_id) => format!("You will do {} this phase", action_id),
+                    None => "You will not do anything this phase".to_string(),
+                };
+                self.submit_uimessage(UIMessage::sys(&text));
+            }
+            ClientAction::RoleReveal(user, role) => {
+                self.submit_uimessage(UIMessage::sys(&format!("{} is {}", user, role)))
+            }
+            ClientAction::Killed(user, role) => {
+                let text = match role {
+                    Some(role) => format!("{} died, they were {}", user, role),
+                    None => format!("{} died", user),
+                };
+                self.submit_uimessage(UIMessage::sys(&text));
+            }
+            ClientAction::PhaseChanged(phase) => {
+                self.submit_uimessage(UIMessage::sys(&format!("The game is now in the {} phase", phase)))
+            }
+            ClientAction::Rewound(seq) => {
+                self.submit_uimessage(UIMessage::sys(&format!("The game was rewound to message {}", seq)))
+            }
+            ClientAction::GroupsChanged(groups) => {
+                self.submit_uimessage(UIMessage::sys(&format!("Your groups are now: {}", groups.join(", "))))
+            }
+            ClientAction::ChatCreated(chat) => {
+                self.submit_uimessage(UIMessage::sys(&format!("Chat {} was opened", chat)))
+            }
+            ClientAction::ChatDeleted(chat) => {
+                self.submit_uimessage(UIMessage::sys(&format!("Chat {} was closed", chat)))
+            }
+            ClientAction::SystemMessage(content) => self.submit_uimessage(UIMessage::sys(content)),
             ClientAction::Error(e) => {
                 self.submit_uimessage(UIMessage::err(&format!("Server Error: {}", e)));
             }
@@ -287,7 +415,10 @@ impl<'a> App<'a> {
         url: String,
         sender: Sender<()>,
     ) -> Result<(), yapnet_client::Error> {
-        self.client = Some(Client::connect(url).await?);
+        match &mut self.client {
+            Some(client) => client.reconnect(url, None).await?,
+            None => self.client = Some(Client::connect(url).await?),
+        }
         sender
             .send(())
             .expect("Message handler is only to be called once");
@@ -302,7 +433,7 @@ pub struct UIMessage {
     content: String,
 }
 
-impl<'t> Display for &'t UIMessage {
+impl Display for &UIMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}@{}: {}", self.player, self.location, self.content)
     }
@@ -362,6 +493,11 @@ enum AppCommand {
     Login(Uuid),
     PlayerList,
     SwitchChat(String),
+    Vote(String),
+    Actions,
+    Act(String, Vec<String>),
+    Rewind(u64),
+    Reload,
     Chat,
     Help,
     Error(String),
@@ -370,7 +506,7 @@ enum AppCommand {
 impl FromStr for AppCommand {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.chars().next().map_or(true, |c| c != '!') {
+        if !s.starts_with('!') {
             return Err(());
         }
 
@@ -402,6 +538,24 @@ impl FromStr for AppCommand {
             "chat" => Ok(tokens
                 .next()
                 .map_or(Self::Chat, |u| Self::SwitchChat(u.to_string()))),
+            "actions" => Ok(Self::Actions),
+            "act" => Ok(tokens
+                .next()
+                .map_or(Self::Error("Missing argument: action".to_string()), |a| {
+                    Self::Act(a.to_string(), tokens.map(|t| t.to_string()).collect())
+                })),
+            "vote" => Ok(tokens
+                .next()
+                .map_or(Self::Error("Missing argument: player".to_string()), |u| {
+                    Self::Vote(u.to_string())
+                })),
+            "rewind" => Ok(tokens
+                .next()
+                .map_or(Self::Error("Missing argument: seq".to_string()), |s| {
+                    s.parse()
+                        .map_or_else(|e| Self::Error(format!("Invalid seq: {:?}", e)), Self::Rewind)
+                })),
+            "reload" => Ok(Self::Reload),
             _ => Ok(Self::Error("Unknown command".to_string())),
         }
     }
@@ -415,5 +569,10 @@ Commands:
  - list           -> list the players 
  - chat           -> list the chats
  - chat chat_name -> switch to this chat 
+ - vote player    -> vote on a player in the current chat
+ - actions        -> list the actions you can do
+ - act id args..  -> do an action
+ - rewind seq     -> go back to before message seq (admins only)
+ - reload         -> load the game script again (admins only)
  - help           -> display this message
 "###;
@@ -25,34 +25,26 @@ pub async fn handle_key_events<'a>(key_event: KeyEvent, app: &mut App<'a>) -> Ap
             return Ok(());
         }
         // Exit application on `Ctrl-C`
-        KeyCode::Char('c') | KeyCode::Char('C') => {
-            if key_event.modifiers == KeyModifiers::CONTROL {
-                app.quit();
-                return Ok(());
-            }
+        KeyCode::Char('c') | KeyCode::Char('C') if key_event.modifiers == KeyModifiers::CONTROL => {
+            app.quit();
+            return Ok(());
         }
-        KeyCode::Char('m') | KeyCode::Char('M') => {
-            if key_event.modifiers == KeyModifiers::CONTROL {
-                app.enter_message().await;
-                return Ok(());
-            }
+        KeyCode::Char('m') | KeyCode::Char('M') if key_event.modifiers == KeyModifiers::CONTROL => {
+            app.enter_message().await;
+            return Ok(());
         }
         KeyCode::Enter => {
             app.enter_message().await;
             return Ok(());
         }
 
-        KeyCode::Up => {
-            if key_event.modifiers.intersects(KeyModifiers::SHIFT) {
-                app.scroll.scroll_up();
-                return Ok(());
-            }
+        KeyCode::Up if key_event.modifiers.intersects(KeyModifiers::SHIFT) => {
+            app.scroll.scroll_up();
+            return Ok(());
         }
-        KeyCode::Down => {
-            if key_event.modifiers.intersects(KeyModifiers::SHIFT) {
-                app.scroll.scroll_down();
-                return Ok(());
-            }
+        KeyCode::Down if key_event.modifiers.intersects(KeyModifiers::SHIFT) => {
+            app.scroll.scroll_down();
+            return Ok(());
         }
 
         _ => {}
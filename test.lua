@@ -19,14 +19,35 @@ Define_chats = function (n)
     local chats = {}
     for i = 1, n, 1 do
        local name = "general" .. i
-       yapi.yn_api_test(name);
        chats[name] = { allowed = "any" }
     end
+    chats["mafia"] = { allowed = "group:mafia" }
+    chats["news"] = { read = { "any" }, write = { "group:admin" } }
     return chats
 end
 
 return {
   chats = Define_chats(15),
+  phases = { "dusk" },
+  roles = {
+    mafia = { known_by = { "mafia" } },
+    detective = {},
+    villager = {},
+  },
+  actions = {
+    inspect = { args = { "player" }, roles = { "detective" } },
+  },
+  assign_roles = function (frame, players)
+    local roles = { "mafia", "detective" }
+    local out = {}
+    for i, name in ipairs(players) do
+      out[name] = roles[i] or "villager"
+      if out[name] == "mafia" then
+        frame:add_group(name, "mafia")
+      end
+    end
+    return out
+  end,
   on_chat = function (frame, t, n, c)
     frame:send_message ({
       msg_type = "chat",
@@ -38,5 +59,31 @@ return {
       }
     });
 
+  end,
+  on_join = function (frame, player)
+    print(player .. " joined the game")
+  end,
+  on_back = function (frame, player)
+    print(player .. " is back")
+  end,
+  on_leave = function (frame, player)
+    print(player .. " left")
+  end,
+  on_action = function (frame, player, action, args)
+    return true, player .. " used " .. action .. " on " .. args[1]
+  end,
+  on_phase_enter = function (frame, phase)
+    print("Entering phase " .. phase)
+    if phase == "day" then
+      yapi.schedule(120, "dusk", { from = phase })
+    end
+  end,
+  on_timer = function (frame, name, payload, id)
+    if name == "dusk" then
+      frame:set_phase("dusk")
+    end
+  end,
+  on_vote_result = function (frame, chat, target)
+    yapi.send_chat(chat, "The vote has ended: " .. (target or "no decision"))
   end
 }
@@ -13,7 +13,6 @@
 //  limitations under the License.
 
 use core::panic;
-use std::collections::HashMap;
 
 use futures_util::{
     stream::{SplitSink, SplitStream},
@@ -23,28 +22,25 @@ use serde_json::{from_str, to_string};
 use tokio_tungstenite::{
     connect_async, tungstenite::protocol::Message as WSMessage, MaybeTlsStream, WebSocketStream,
 };
-use yapnet_core::{models::chat::MessageRef, prelude::*};
-
-macro_rules! unpack_msg {
-    {$e:expr, $t:pat => $b:block }=> {
-      match &$e.data {
-        $t => $b
-        _ => unreachable!()
-      }
-    };
-}
-
-struct RecapInfo {
-    current_seq: usize,
-    end_chunk: usize,
-    chunk_sz: usize,
-}
+use yapnet_core::{
+    models::{
+        chat::MessageRef,
+        game::{Game, Player},
+    },
+    prelude::*,
+    protocol::recap::{RecapError, RecapReader},
+};
 
 pub struct GameState {
     pub messages: Vec<Message>,
     pub registered: bool,
     pub username: Option<String>,
     pub token: Option<uuid::Uuid>,
+    pub readable_chats: Vec<String>,
+    /// Seq of the last rewind the client went through
+    pub last_rewind: Option<u64>,
+    /// Last seq of a saved sync point, used until the client has newer messages
+    saved_seq: Option<u64>,
 }
 
 impl GameState {
@@ -54,51 +50,45 @@ impl GameState {
             registered: false,
             username: None,
             token: None,
+            readable_chats: vec![],
+            last_rewind: None,
+            saved_seq: None,
         }
     }
 
     fn get_pending_index(&self) -> usize {
         self.messages.len()
     }
-}
 
-pub struct PlayerState {
-    pub username: String,
-    pub connected: bool,
-    pub role: String,
-}
-
-impl PlayerState {
-    fn new(username: String) -> Self {
-        Self {
-            username,
-            connected: true,
-            role: "__default".to_string(),
-        }
+    /// Seq of the newest message the client has, the server only has to send what came after
+    pub fn last_seq(&self) -> Option<u64> {
+        self.messages.last().map(|m| m.seq).or(self.saved_seq)
     }
 }
 
-pub struct LobbyState {
-    pub players: HashMap<String, PlayerState>,
-    pub chats: HashMap<String, Chat>,
+/// Where the client is in the history, saved next to the token so logging back in only fetches what was missed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncPoint {
+    pub last_seq: Option<u64>,
+    pub last_rewind: Option<u64>,
 }
 
-fn blank_handler(_client: &Client, _msg: &Message) {}
+/// The game as the client sees it, built with the same reducer as the server
+pub type LobbyState = Game;
+
+type ClientReader = SplitStream<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>>;
+type ClientWriter = SplitSink<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>, WSMessage>;
 
-impl LobbyState {
-    fn new() -> Self {
-        Self {
-            chats: HashMap::new(),
-            players: HashMap::new(),
-        }
-    }
-}
 pub struct Client {
-    reader: SplitStream<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>>,
-    writer: SplitSink<WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>, WSMessage>,
+    reader: ClientReader,
+    writer: ClientWriter,
     pub state: GameState,
     pub lobby: LobbyState,
-    recap_info: Option<RecapInfo>,
+    recap: Option<RecapReader>,
+    /// Ack or resync request to send once the message is handled
+    reply: Option<MessageData>,
+    /// Messages were missed, live messages are dropped until the recap fills the gap
+    resyncing: bool,
 }
 
 pub type ClientResult = Result<ClientAction, Error>;
@@ -110,6 +100,18 @@ pub enum ClientAction {
     PlayerJoined(String),
     PlayerLeft(String),
     Chat(MessageRef),
+    Vote(MessageRef),
+    ActionsChanged,
+    ActionResult(String, bool, String),
+    ActionSelected(Option<String>),
+    RoleReveal(String, String),
+    Killed(String, Option<String>),
+    PhaseChanged(Phase),
+    GroupsChanged(Vec<String>),
+    ChatCreated(String),
+    ChatDeleted(String),
+    SystemMessage(String),
+    Rewound(u64),
     RecapEnd,
     Error(String),
     Multiple(Vec<ClientResult>),
@@ -117,28 +119,63 @@ pub enum ClientAction {
 
 impl Client {
     pub async fn connect(url: String) -> Result<Self, Error> {
-        let (stream, _response) = connect_async(url).await.map_err(Error::Websocket)?;
-        // TODO: Validate connection more
-        //
-        let (writer, reader) = stream.split();
+        let (writer, reader) = Self::open_socket(url).await?;
 
         Ok(Self {
             writer,
             reader,
             state: GameState::new(),
             lobby: LobbyState::new(),
-            recap_info: None,
+            recap: None,
+            reply: None,
+            resyncing: false,
         })
     }
 
+    /// Connects again while keeping the messages, so logging in only fetches what was missed.
+    /// A client that did not keep the messages, like one that was restarted, can pass the sync point it saved.
+    pub async fn reconnect(&mut self, url: String, from: Option<SyncPoint>) -> Result<(), Error> {
+        (self.writer, self.reader) = Self::open_socket(url).await?;
+        if let Some(from) = from {
+            self.state.messages.clear();
+            self.state.saved_seq = from.last_seq;
+            self.state.last_rewind = from.last_rewind;
+        }
+        self.state.registered = false;
+        self.recap = None;
+        self.reply = None;
+        self.resyncing = false;
+        Ok(())
+    }
+
+    async fn open_socket(url: String) -> Result<(ClientWriter, ClientReader), Error> {
+        let (stream, _response) = connect_async(url).await.map_err(|e| Error::Websocket(Box::new(e)))?;
+        // TODO: Validate connection more
+        //
+        Ok(stream.split())
+    }
+
+    /// What to save to reconnect later, see reconnect
+    pub fn sync_point(&self) -> SyncPoint {
+        SyncPoint {
+            last_seq: self.state.last_seq(),
+            last_rewind: self.state.last_rewind,
+        }
+    }
+
+    /// The player of this client, once the server told about it
+    pub fn me(&self) -> Option<&Player> {
+        self.lobby.players.get(self.state.username.as_ref()?)
+    }
+
     async fn send_message_pre(&mut self, msg: MessageData) -> Result<(), Error> {
-        let wrapped = Message { seq: 0, data: msg };
+        let wrapped = Message { seq: 0, prev: None, data: msg };
         self.writer
             .send(WSMessage::Text(
                 to_string(&wrapped).expect("Serialization should never fail here"),
             ))
             .await
-            .map_err(Error::Websocket)
+            .map_err(|e| Error::Websocket(Box::new(e)))
     }
 
     pub async fn send_register(&mut self, username: String) {
@@ -155,14 +192,19 @@ impl Client {
     }
 
     pub async fn send_login(&mut self, token: uuid::Uuid) {
-        self.send_message_pre(Back { token }.into()).await.unwrap();
+        let SyncPoint { last_seq, last_rewind } = self.sync_point();
+        self.send_message_pre(Back { token, last_seq, last_rewind }.into()).await.unwrap();
     }
 
     pub async fn recieve_and_handle(&mut self) -> ClientResult {
-        match self.reader.next().await.unwrap() {
+        let res = match self.reader.next().await.unwrap() {
             Ok(msg) => self.handle_ws(msg),
-            Err(e) => Err(Error::Websocket(e)),
+            Err(e) => Err(Error::Websocket(Box::new(e))),
+        };
+        if let Some(reply) = self.reply.take() {
+            self.send_message_pre(reply).await?;
         }
+        res
     }
 
     fn handle_ws(&mut self, wsm: WSMessage) -> ClientResult {
@@ -174,6 +216,21 @@ impl Client {
     }
 
     fn handle_message(&mut self, msg: Message) -> ClientResult {
+        // Live messages point back to the one before, the recap will have it if it went missing
+        if let Some(prev) = msg.prev {
+            if self.resyncing {
+                return Ok(ClientAction::None);
+            }
+            if Some(prev) != self.state.last_seq() {
+                tracing::warn!("Missed messages between {} and {}, resyncing", prev, msg.seq);
+                self.resyncing = true;
+                let SyncPoint { last_seq, last_rewind } = self.sync_point();
+                self.reply = Some(Resync { last_seq, last_rewind }.into());
+                return Ok(ClientAction::None);
+            }
+        }
+
+        let live = msg.prev.is_some();
         let ret: ClientResultOuter = match msg.data {
             MessageData::BodyYnError(ref err) => ClientResultOuter(
                 Ok(ClientAction::Error(format!(
@@ -189,9 +246,36 @@ impl Client {
             MessageData::BodySetup(ref x) => self.handle_setup(x),
             MessageData::BodyRecapHead(ref x) => self.start_recap(x),
             MessageData::BodyRecapTail(ref x) => self.progress_recap(x),
+            MessageData::BodyVoteStart(_)
+            | MessageData::BodyVoteSubmission(_)
+            | MessageData::BodyVoteResult(_) => self.handle_vote(),
+            MessageData::BodyAvailableActions(ref x) => self.handle_available_actions(x),
+            MessageData::BodyActionResult(ref x) => self.handle_action_result(x),
+            MessageData::BodyActionSelected(ActionSelected { ref action_id, .. }) => {
+                ClientResultOuter(Ok(ClientAction::ActionSelected(action_id.clone())), true)
+            }
+            MessageData::BodyRoleReveal(ref x) => self.handle_role_reveal(x),
+            MessageData::BodyKillReveal(ref x) => self.handle_kill_reveal(x),
+            MessageData::BodyPhaseChange(ref x) => self.handle_phase_change(x),
+            MessageData::BodyGroupsChanged(ref x) => self.handle_groups_changed(x),
+            MessageData::BodyRewind(ref x) => self.handle_rewind(x),
+            MessageData::BodyChatCreated(ChatCreated { ref chat }) => {
+                ClientResultOuter(Ok(ClientAction::ChatCreated(chat.name.clone())), true)
+            }
+            MessageData::BodyChatDeleted(ChatDeleted { ref name }) => {
+                ClientResultOuter(Ok(ClientAction::ChatDeleted(name.clone())), true)
+            }
+            MessageData::BodySystemMessage(SystemMessage { ref content, .. }) => {
+                ClientResultOuter(Ok(ClientAction::SystemMessage(content.clone())), true)
+            }
             d @ MessageData::BodyChatSend(..)
+            | d @ MessageData::BodySubmitVote(..)
+            | d @ MessageData::BodySubmitAction(..)
             | d @ MessageData::BodyHello(..)
             | d @ MessageData::BodyBack(..)
+            | d @ MessageData::BodyAck(..)
+            | d @ MessageData::BodyResync(..)
+            | d @ MessageData::BodyReload(..)
             | d @ MessageData::BodyEcho(..) => panic!("Message for server sent here: {:?}", d),
             x => {
                 tracing::error!("Unknown message [{}] handled.", x.to_inner().msg_type());
@@ -200,43 +284,41 @@ impl Client {
         };
 
         if ret.1 {
-            self.state.messages.push(msg);
+            let seq = msg.seq;
+            self.store(msg);
+            if live {
+                self.reply = Some(Ack { seq }.into());
+            }
         }
         ret.0
     }
 
+    /// Keeps the message and applies it to the lobby
+    fn store(&mut self, msg: Message) {
+        let ind = self.state.get_pending_index();
+        self.lobby.reduce(&msg.data);
+        if let MessageData::BodyChatSent(ChatSent { ref chat_target, .. }) = msg.data {
+            if let Some(chat) = self.lobby.chats.get_mut(chat_target) {
+                chat.messages.push(ind);
+            }
+        }
+        self.state.messages.push(msg);
+    }
+
     fn handle_player_joined(&mut self, msg: &PlayerJoined) -> ClientResultOuter {
         assert!(self.state.registered);
-        let uname = msg.username.clone();
-        if uname != self.state.username.clone().unwrap() {
-            if let Some(player) = self.lobby.players.get_mut(&msg.username) {
-                if player.connected {
-                    eprintln!("Double player connection")
-                }
-                player.connected = true;
-            } else {
-                let player = PlayerState::new(uname.clone());
-                self.lobby.players.insert(uname.clone(), player);
-            }
+        if self.lobby.players.get(&msg.username).is_some_and(|p| p.online) {
+            eprintln!("Double player connection")
         }
-        ClientResultOuter(Ok(ClientAction::PlayerJoined(uname)), true)
+        ClientResultOuter(Ok(ClientAction::PlayerJoined(msg.username.clone())), true)
     }
 
     fn handle_player_left(&mut self, msg: &PlayerLeft) -> ClientResultOuter {
         assert!(self.state.registered);
-        let uname = msg.username.clone();
-        if uname != self.state.username.clone().unwrap() {
-            if let Some(player) = self.lobby.players.get_mut(&msg.username) {
-                if !player.connected {
-                    eprintln!("Double player disconnection")
-                }
-                player.connected = false;
-            } else {
-                let player = PlayerState::new(uname.clone());
-                self.lobby.players.insert(uname.clone(), player).unwrap();
-            }
+        if self.lobby.players.get(&msg.username).is_some_and(|p| !p.online) {
+            eprintln!("Double player disconnection")
         }
-        ClientResultOuter(Ok(ClientAction::PlayerJoined(uname)), true)
+        ClientResultOuter(Ok(ClientAction::PlayerLeft(msg.username.clone())), true)
     }
     fn handle_welcome(&mut self, Welcome { username, token }: &Welcome) -> ClientResultOuter {
         self.state.username = Some(username.clone());
@@ -244,64 +326,111 @@ impl Client {
         self.state.registered = true;
         ClientResultOuter(Ok(ClientAction::Welcome), false)
     }
-    fn handle_chat(&mut self, ChatSent { chat_target, .. }: &ChatSent) -> ClientResultOuter {
+    fn handle_chat(&mut self, _: &ChatSent) -> ClientResultOuter {
         let ind = self.state.get_pending_index();
-        self.lobby
-            .chats
-            .get_mut(chat_target)
-            .unwrap()
-            .messages
-            .push(ind);
         ClientResultOuter(Ok(ClientAction::Chat(ind)), true)
     }
 
-    fn handle_setup(&mut self, Setup { chats }: &Setup) -> ClientResultOuter {
-        for chat in chats {
-            self.lobby
-                .chats
-                .insert(chat.name.clone(), Chat::new(chat.perm.clone()));
-        }
-        ClientResultOuter(Ok(ClientAction::None), true)
+    fn handle_vote(&mut self) -> ClientResultOuter {
+        let ind = self.state.get_pending_index();
+        ClientResultOuter(Ok(ClientAction::Vote(ind)), true)
     }
 
-    fn start_recap(&mut self, RecapHead { count, chunk_sz }: &RecapHead) -> ClientResultOuter {
-        self.recap_info = Some(RecapInfo {
-            chunk_sz: *chunk_sz,
-            end_chunk: *count,
-            current_seq: 0,
-        });
-        ClientResultOuter(Ok(ClientAction::None), false)
+    fn handle_available_actions(&mut self, _: &AvailableActions) -> ClientResultOuter {
+        ClientResultOuter(Ok(ClientAction::ActionsChanged), true)
     }
 
-    fn progress_recap(&mut self, RecapTail { start, msgs }: &RecapTail) -> ClientResultOuter {
-        let mut actions: Vec<ClientResult> = vec![];
-        let (_, mut current) = match &self.recap_info {
-            Some(recap) => (recap.end_chunk, recap.current_seq),
-            None => {
-                return ClientResultOuter(Err(Error::NoRecapHead), false);
-            }
-        };
+    fn handle_action_result(
+        &mut self,
+        ActionResult {
+            action_id,
+            success,
+            reason,
+        }: &ActionResult,
+    ) -> ClientResultOuter {
+        ClientResultOuter(
+            Ok(ClientAction::ActionResult(
+                action_id.clone(),
+                *success,
+                reason.clone(),
+            )),
+            false,
+        )
+    }
 
-        assert_eq!(*start, current);
-        for msgv in msgs {
-            let msg: Message = serde_json::from_value(msgv.clone()).unwrap();
+    fn handle_role_reveal(&mut self, RoleReveal { user, role, .. }: &RoleReveal) -> ClientResultOuter {
+        ClientResultOuter(
+            Ok(ClientAction::RoleReveal(user.clone(), role.clone())),
+            true,
+        )
+    }
 
-            // debug_assert_eq!(msg.seq as usize, current);
+    fn handle_kill_reveal(&mut self, KillReveal { user, role }: &KillReveal) -> ClientResultOuter {
+        ClientResultOuter(Ok(ClientAction::Killed(user.clone(), role.clone())), true)
+    }
 
-            // Todo: Make sure that the chunks are counted properly
-            // debug_assert!((msg.seq as usize) <= end);
+    fn handle_phase_change(&mut self, PhaseChange { phase }: &PhaseChange) -> ClientResultOuter {
+        ClientResultOuter(Ok(ClientAction::PhaseChanged(phase.clone())), true)
+    }
 
-            current += 1;
+    fn handle_rewind(&mut self, Rewind { seq }: &Rewind) -> ClientResultOuter {
+        // The lobby cannot be undone, so it is built again from what is left
+        let messages = std::mem::take(&mut self.state.messages);
+        self.lobby = LobbyState::new();
+        for msg in messages.into_iter().filter(|m| m.seq < *seq) {
+            self.store(msg);
+        }
+        self.state.saved_seq = self.state.saved_seq.filter(|s| s < seq);
+        self.state.last_rewind = Some(*seq);
+        ClientResultOuter(Ok(ClientAction::Rewound(*seq)), true)
+    }
 
-            actions.push(self.handle_message(msg))
+    fn handle_groups_changed(&mut self, GroupsChanged { user, groups, chats }: &GroupsChanged) -> ClientResultOuter {
+        if Some(user) == self.state.username.as_ref() {
+            self.state.readable_chats = chats.clone();
         }
+        ClientResultOuter(Ok(ClientAction::GroupsChanged(groups.clone())), true)
+    }
+
+    fn handle_setup(&mut self, _: &Setup) -> ClientResultOuter {
+        ClientResultOuter(Ok(ClientAction::None), true)
+    }
 
-        self.recap_info.as_mut().unwrap().current_seq = current;
+    fn start_recap(&mut self, head: &RecapHead) -> ClientResultOuter {
+        self.recap = Some(RecapReader::new(head));
+        ClientResultOuter(Ok(ClientAction::None), false)
+    }
+
+    fn progress_recap(&mut self, tail: &RecapTail) -> ClientResultOuter {
+        let Some(recap) = self.recap.as_mut() else {
+            return ClientResultOuter(Err(Error::NoRecapHead), false);
+        };
+        let msgs = match recap.read(tail) {
+            Ok(msgs) => msgs,
+            Err(e) => {
+                // Part of the recap is missing, so it is asked for again from what the client has
+                self.recap = None;
+                self.resyncing = false;
+                let SyncPoint { last_seq, last_rewind } = self.sync_point();
+                self.reply = Some(Resync { last_seq, last_rewind }.into());
+                return ClientResultOuter(Err(Error::Recap(e)), false);
+            }
+        };
+        let done = recap.is_done();
 
-        let recap = self.recap_info.as_ref().unwrap();
-        if recap.current_seq >= recap.end_chunk {
+        // Resyncs can overlap, so anything the client already has is skipped, rewinds included
+        let mut actions: Vec<ClientResult> = vec![];
+        for msg in msgs {
+            let new_rewind = matches!(msg.data, MessageData::BodyRewind(_)) && Some(msg.seq) != self.state.last_rewind;
+            if Some(msg.seq) > self.state.last_seq() || new_rewind {
+                actions.push(self.handle_message(msg));
+            }
+        }
+        if done {
             actions.push(Ok(ClientAction::RecapEnd));
-            self.recap_info = None;
+            self.recap = None;
+            self.resyncing = false;
+            self.reply = self.state.last_seq().map(|seq| Ack { seq }.into());
         }
 
         ClientResultOuter(Ok(ClientAction::Multiple(actions)), false)
@@ -311,6 +440,43 @@ impl Client {
 #[derive(Debug)]
 pub enum Error {
     Unregistered,
-    Websocket(tungstenite::Error),
+    Websocket(Box<tungstenite::Error>),
     NoRecapHead,
+    Recap(RecapError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// A client connected to a socket that never says anything
+    async fn quiet_client() -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        let client = Client::connect(url).await.unwrap();
+        server.await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn a_broken_recap_is_asked_for_again() {
+        let mut client = quiet_client().await;
+        client.resyncing = true;
+
+        assert!(client.handle_message(RecapHead { count: 2, chunk_sz: 1 }.into_message()).is_ok());
+        let skipped = RecapTail { index: 1, start: 1, last: true, msgs: vec![] };
+        assert!(matches!(
+            client.handle_message(skipped.into_message()),
+            Err(Error::Recap(RecapError::OutOfOrder { expected: 0, got: 1 }))
+        ));
+
+        assert!(!client.resyncing);
+        assert!(client.recap.is_none());
+        assert!(matches!(client.reply, Some(MessageData::BodyResync(Resync { last_seq: None, last_rewind: None }))));
+    }
 }
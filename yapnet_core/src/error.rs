@@ -18,22 +18,22 @@ impl From<ClientError> for YnError {
     fn from(value: ClientError) -> Self {
         match value {
             ClientError::NameTaken(name) 
-            => Self::new("NameTaken", &format!("The name: {} is taken", name), 
+            => Self::new("NameTaken", format!("The name: {} is taken", name), 
                 format!("{{ \"invalid_name\": \"{}\" }}",name)),
             ClientError::InvalidToken
             => Self::new("InvalidToken", "The token you gave is invalid", ""),
             ClientError::NoLogin 
             => Self::new("NoLogin", "The action requires login", ""),
             ClientError::NoPermission(object, reason)
-            => Self::new("NoPermission", &format!("The action on {} requires permissions you don't have", object), simple_json_object("reason", reason)),
+            => Self::new("NoPermission", format!("The action on {} requires permissions you don't have", object), simple_json_object("reason", reason)),
             ClientError::InvalidObject(id, reason)  
-            => Self::new("InvalidObject", &format!("{}, cannot be the object of that action", id), simple_json_object("reason", reason)),
+            => Self::new("InvalidObject", format!("{}, cannot be the object of that action", id), simple_json_object("reason", reason)),
             ClientError::InvalidSubject(id, reason)
-            => Self::new("InvalidSubject", &format!("{}, cannot be the subject of that action", id), simple_json_object("reason", reason)),
+            => Self::new("InvalidSubject", format!("{}, cannot be the subject of that action", id), simple_json_object("reason", reason)),
             ClientError::InvalidChat(id, reason)
-            => Self::new("InvalidChat", &format!("{}, cannot be targeted for that action", id), simple_json_object("reason", reason)),
+            => Self::new("InvalidChat", format!("{}, cannot be targeted for that action", id), simple_json_object("reason", reason)),
             ClientError::InvalidAction(id, reason)
-            => Self::new("InvalidAction", &format!("the action, {}, cannot be performed", id), simple_json_object("reason", reason)),
+            => Self::new("InvalidAction", format!("the action, {}, cannot be performed", id), simple_json_object("reason", reason)),
             ClientError::Custom(info, details)
             => Self::new("Custom", info, details),
         }
@@ -67,6 +67,10 @@ pub enum ServerError {
     InvalidToken,
     AlreadyJoinedOrLeft, 
     NameTaken(String), 
+    /// The callback went over a limit of the script runtime and was aborted
+    ScriptLimit(String, String),
+    /// A callback of the game script failed: the callback, the error and the traceback
+    ScriptError(String, String, String),
     Custom(String, String),
 }
 
@@ -80,6 +84,10 @@ impl From<ServerError> for YnError {
             ServerError::NameTaken(name) 
             => Self::new("NameTaken", format!("The name: {} is taken", name), 
                 format!("{{ \"invalid_name\": \"{}\" }}",name)),
+            ServerError::ScriptLimit(callback, limit)
+            => Self::new("ScriptLimit", format!("The callback {} was aborted", callback), simple_json_object("reason", limit)),
+            ServerError::ScriptError(callback, error, traceback)
+            => Self::new("ScriptError", format!("The callback {} failed: {}", callback, error), traceback),
             ServerError::Custom(info, details)
             => Self::new("ServerError", info, details),
         }
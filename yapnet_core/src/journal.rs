@@ -0,0 +1,131 @@
+// Copyright 2025 Jakub Stachurski
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    models::{timer::Timers, user::Users},
+    protocol::MessageV2,
+};
+
+/// The parts of the state that cannot be rebuilt from the history alone
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub users: Users,
+    pub timers: Timers,
+}
+
+/// Borrowed version of the snapshot, used for writing it
+#[derive(Serialize)]
+pub struct SnapshotRef<'a> {
+    pub users: &'a Users,
+    pub timers: &'a Timers,
+}
+
+/// Append-only record of the history on disk, one message per line, next to a snapshot of the state.
+pub struct Journal {
+    dir: PathBuf,
+    file: File,
+    /// Sequence number of the next message to write
+    written: u64,
+}
+
+impl Journal {
+    const JOURNAL_FILE: &'static str = "journal.jsonl";
+    const SNAPSHOT_FILE: &'static str = "snapshot.json";
+
+    /// Starts a new journal in the directory.
+    /// An old journal there is only thrown away with overwrite, otherwise this fails with AlreadyExists.
+    pub fn create(dir: &Path, overwrite: bool) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(overwrite)
+            .create_new(!overwrite)
+            .open(dir.join(Self::JOURNAL_FILE))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            file,
+            written: 0,
+        })
+    }
+
+    /// Reads the journal and the snapshot, and opens the journal to continue it
+    pub fn open(dir: &Path) -> io::Result<(Self, Vec<MessageV2>, Snapshot)> {
+        let mut messages = Vec::new();
+        let reader = BufReader::new(File::open(dir.join(Self::JOURNAL_FILE))?);
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            messages.push(serde_json::from_str(&line)?);
+        }
+
+        let snapshot = serde_json::from_str(&fs::read_to_string(dir.join(Self::SNAPSHOT_FILE))?)?;
+
+        let file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(Self::JOURNAL_FILE))?;
+        let written = messages.last().map_or(0, |m: &MessageV2| m.seq + 1);
+        Ok((
+            Self {
+                dir: dir.to_path_buf(),
+                file,
+                written,
+            },
+            messages,
+            snapshot,
+        ))
+    }
+
+    /// Starts the journal over with these messages, used when the history is rewound
+    pub fn rewrite<'a>(&mut self, messages: impl Iterator<Item = &'a MessageV2>) -> io::Result<()> {
+        self.file = File::create(self.dir.join(Self::JOURNAL_FILE))?;
+        self.written = 0;
+        self.append(messages)
+    }
+
+    /// Sequence number of the next message the journal expects
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Appends messages, the ones that were already written are skipped
+    pub fn append<'a>(&mut self, messages: impl Iterator<Item = &'a MessageV2>) -> io::Result<()> {
+        for m in messages {
+            if m.seq < self.written {
+                continue;
+            }
+            let line = serde_json::to_string(m)?;
+            writeln!(self.file, "{}", line)?;
+            self.written = m.seq + 1;
+        }
+        self.file.flush()
+    }
+
+    /// Replaces the snapshot, going through a temporary file so a crash never leaves half of it
+    pub fn snapshot(&self, snapshot: &SnapshotRef) -> io::Result<()> {
+        let tmp = self.dir.join(format!("{}.tmp", Self::SNAPSHOT_FILE));
+        fs::write(&tmp, serde_json::to_vec(snapshot)?)?;
+        fs::rename(tmp, self.dir.join(Self::SNAPSHOT_FILE))
+    }
+}
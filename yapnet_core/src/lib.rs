@@ -15,6 +15,7 @@
 pub mod models;
 pub mod state;
 pub mod error;
+pub mod journal;
 pub mod lua;
 pub mod prelude;
 pub mod protocol;
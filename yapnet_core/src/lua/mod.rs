@@ -12,13 +12,15 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
+pub mod schema;
 pub mod yapi;
 
 use mlua::prelude::*;
 
+/// Reads a field, missing fields get the default
 pub fn parse_table_field<'t, T: FromLua<'t>>(table: LuaTable<'t>, name: &'t str, default: T) -> T {
-    match table.get(name) {
-        Ok(a) => a,
+    match table.get::<_, Option<T>>(name) {
+        Ok(a) => a.unwrap_or(default),
         Err(e) => {
             eprintln!("Missing '{name}' field: {}", e);
             default
@@ -28,81 +30,498 @@ pub fn parse_table_field<'t, T: FromLua<'t>>(table: LuaTable<'t>, name: &'t str,
 
 use std::vec;
 
-use mlua::prelude::*;
+use crate::prelude::{
+    ActionSelected, ChatCreated, ChatDeleted, ChatSent, ChatSetup, GroupsChanged, IntoMessage, KillReveal, PhaseChange, RoleReveal,
+    SystemMessage, VoteStart,
+};
+use crate::error::ServerError;
 use crate::state::YapnetState;
+use schema::{Problem, SchemaReport, Severity};
+use chrono::{TimeDelta, Utc};
 use std::sync::Mutex;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{BTreeSet, HashMap}, sync::Arc};
 use crate::protocol::{MessageV2 as Message, Perms};
-use crate::{models::chat::Chat, protocol::Perm};
+use crate::{models::{action::{ActionDef, Actions}, role::{RoleDef, Roles}, timer::{Timer, TimerId}}, protocol::{ActionArg, Perm, Phase}};
 
 // use mlua::LuaSerdeExt;
 use yapi::LuaPlayer;
 
 pub async fn init_lua() {}
 
-pub fn state_init(lua: Lua) -> YapnetState {
+/// Builds the state from the game script, a script that does not pass [schema::validate] is refused
+pub fn state_init(lua: Lua) -> Result<YapnetState, SchemaReport> {
+    let script = read_game(&lua)?;
     let mut state = YapnetState::new();
-    {
-        let globals = lua.globals();
-
-        let game: LuaTable = globals
-            .get("__game")
-            .expect("Cannot find the configuration chunk ( return { .. } )");
-        let chats: LuaTable = game.get("chats").expect("Cannot find chats!");
-        for pair in chats.pairs::<String, LuaTable>() {
-            match pair {
-                Ok((name, table)) => {
-                    let allowed_group: String =
-                        parse_table_field(table, "allowed", "none".to_string());
-
-                    let perm = match allowed_group.as_str() {
-                        "any" | "all" => Perm::Any { rw: 3 },
-                        "none" => Perm::User {
-                            rw: 3,
-                            name: String::from("__Noone"),
-                        },
-                        g => Perm::Group {
-                            rw: 3,
-                            name: g.to_string(),
-                        },
-                    };
-
-                    let v = Chat {
-                        perms: Perms::wrap_vec(vec![perm]),
-                        messages: vec![],
-                    };
-                    state.chats.insert(name.clone(), v);
-                }
-                Err(e) => eprintln!("Cannot parse chat: {}", e),
+    state.phases = script.phases;
+    state.roles = script.roles;
+    state.actions = script.actions;
+    state.lua_state = Some(LuaState { lua });
+    state.push_setup_message(script.chats);
+
+    Ok(state)
+}
+
+/// What the game script declares in `__game`
+pub struct GameScript {
+    pub chats: Vec<ChatSetup>,
+    pub phases: Vec<Phase>,
+    pub roles: Roles,
+    pub actions: Actions,
+}
+
+/// Checks and reads the `__game` table, the warnings are printed
+pub fn read_game(lua: &Lua) -> Result<GameScript, SchemaReport> {
+    let report = schema::validate(lua);
+    if report.has_errors() {
+        return Err(report);
+    }
+    for warning in report.warnings() {
+        eprintln!("{}", warning);
+    }
+    parse_game(lua).map_err(|e| SchemaReport {
+        problems: vec![Problem {
+            severity: Severity::Error,
+            path: "__game".to_string(),
+            message: e.to_string(),
+        }],
+    })
+}
+
+fn parse_game(lua: &Lua) -> LuaResult<GameScript> {
+    let game: LuaTable = lua.globals().get("__game")?;
+    let chats: LuaTable = game.get("chats")?;
+
+    let mut setup = vec![];
+    for pair in chats.pairs::<String, LuaTable>() {
+        match pair.and_then(|(name, table)| Ok((name, parse_chat_perms(&table)?, table))) {
+            Ok((name, perm, table)) => {
+                setup.push(ChatSetup {
+                    name,
+                    perm,
+                    phases: parse_phases(table),
+                });
             }
+            Err(e) => eprintln!("Cannot parse chat: {}", e),
         }
     }
 
-    state.lua_state = Some(LuaState { lua });
-    state.push_setup_message();
+    let mut roles = Roles::new();
+    let role_table: Option<LuaTable> = game.get("roles").unwrap_or(None);
+    for pair in role_table.into_iter().flat_map(|r| r.pairs::<String, LuaTable>()) {
+        match pair {
+            Ok((id, table)) => {
+                let role = RoleDef::new(
+                    parse_table_field(table.clone(), "known_by", vec![]),
+                    parse_table_field(table, "reveal_on_death", true),
+                );
+                roles.insert(id, role);
+            }
+            Err(e) => eprintln!("Cannot parse role: {}", e),
+        }
+    }
+
+    let mut actions = Actions::new();
+    let action_table: Option<LuaTable> = game.get("actions").unwrap_or(None);
+    for pair in action_table.into_iter().flat_map(|a| a.pairs::<String, LuaTable>()) {
+        match pair.and_then(|(id, table)| Ok((id, parse_action(table)?))) {
+            Ok((id, action)) => {
+                actions.insert(id, action);
+            }
+            Err(e) => eprintln!("Cannot parse action: {}", e),
+        }
+    }
+
+    Ok(GameScript {
+        chats: setup,
+        phases: parse_phases(game),
+        roles,
+        actions,
+    })
+}
+
+/// Who a chat rule is about: "any", "none", "user:name", or a group as "group:name" or just the name
+fn parse_target(target: &str, rw: u8) -> Option<Perm> {
+    match target.split_once(':') {
+        Some(("user", name)) => Some(Perm::User { rw, name: name.to_string() }),
+        Some(("group", name)) => Some(Perm::Group { rw, name: name.to_string() }),
+        _ => match target {
+            "any" | "all" => Some(Perm::Any { rw }),
+            "none" => None,
+            g => Some(Perm::Group { rw, name: g.to_string() }),
+        },
+    }
+}
+
+/// Who can read and write in a chat, the rules add up:
+/// - `allowed`: a target that can read and write
+/// - `read` and `write`: lists of targets that can only read or only write
+/// - `rules`: a list of `{ user = name | group = name | any = true, rw = 1 | 2 | 3 }`
+///
+/// A chat without any rules can not be used by anyone.
+fn parse_chat_perms(table: &LuaTable) -> LuaResult<Perms> {
+    let mut perms = vec![];
+    let allowed: Option<String> = table.get("allowed")?;
+    perms.extend(allowed.and_then(|a| parse_target(&a, 3)));
+    for (key, rw) in [("read", 1), ("write", 2)] {
+        let targets: Option<Vec<String>> = table.get(key)?;
+        perms.extend(targets.unwrap_or_default().iter().filter_map(|t| parse_target(t, rw)));
+    }
+    let rules: Option<Vec<LuaTable>> = table.get("rules")?;
+    for rule in rules.unwrap_or_default() {
+        perms.push(parse_rule(&rule)?);
+    }
+    Ok(Perms::wrap_vec(perms))
+}
+
+fn parse_rule(rule: &LuaTable) -> LuaResult<Perm> {
+    let rw: u8 = rule.get::<_, Option<u8>>("rw")?.unwrap_or(3);
+    if rw == 0 || rw > 3 {
+        return Err(LuaError::runtime(format!("rw has to be 1, 2 or 3, not {}", rw)));
+    }
+    let user: Option<String> = rule.get("user")?;
+    let group: Option<String> = rule.get("group")?;
+    let any: bool = rule.get::<_, Option<bool>>("any")?.unwrap_or(false);
+    match (user, group, any) {
+        (Some(name), None, false) => Ok(Perm::User { rw, name }),
+        (None, Some(name), false) => Ok(Perm::Group { rw, name }),
+        (None, None, true) => Ok(Perm::Any { rw }),
+        _ => Err(LuaError::runtime("a rule needs exactly one of user, group or any")),
+    }
+}
 
-    state
+fn parse_phases(table: LuaTable) -> Vec<Phase> {
+    let phases: Vec<String> = parse_table_field(table, "phases", vec![]);
+    phases.into_iter().map(Phase::from).collect()
+}
+
+fn parse_action(table: LuaTable) -> LuaResult<ActionDef> {
+    let args: Vec<String> = parse_table_field(table.clone(), "args", vec![]);
+    let args = args
+        .iter()
+        .map(|a| match a.as_str() {
+            "player" => Ok(ActionArg::Player),
+            "chat" => Ok(ActionArg::Chat),
+            "text" => Ok(ActionArg::Text),
+            x => Err(LuaError::external(format!("Unknown argument type: {}", x))),
+        })
+        .collect::<LuaResult<Vec<ActionArg>>>()?;
+
+    Ok(ActionDef::new(
+        args,
+        parse_table_field(table.clone(), "roles", vec![]),
+        parse_phases(table),
+    ))
 }
 
 pub struct LuaState {
     pub lua: Lua,
 }
 
+/// What the game script is allowed to use, so a broken script cannot hang the server
+#[derive(Clone, Copy, Debug)]
+pub struct LuaLimits {
+    /// Instructions a single callback can run before it is aborted
+    pub instructions: u32,
+    /// Bytes the whole runtime can allocate
+    pub memory: usize,
+}
+
+impl LuaLimits {
+    pub const DEFAULT_INSTRUCTIONS: u32 = 10_000_000;
+    pub const DEFAULT_MEMORY: usize = 64 * 1024 * 1024;
+}
+
+impl Default for LuaLimits {
+    fn default() -> Self {
+        Self {
+            instructions: Self::DEFAULT_INSTRUCTIONS,
+            memory: Self::DEFAULT_MEMORY,
+        }
+    }
+}
+
+/// Puts the limits on the runtime, the instruction limit is kept in the app data
+/// and used by [run_limited]
+pub fn set_limits(lua: &Lua, limits: LuaLimits) -> LuaResult<()> {
+    lua.set_memory_limit(limits.memory)?;
+    lua.set_app_data(limits);
+    Ok(())
+}
+
+/// Raised from the instruction hook when the script runs for too long
+#[derive(Debug)]
+struct InstructionLimit(u32);
+
+impl std::fmt::Display for InstructionLimit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ran for more than {} instructions", self.0)
+    }
+}
+
+impl std::error::Error for InstructionLimit {}
+
+/// Runs the closure under the instruction limit of the runtime, if it has one
+pub fn run_limited<R>(lua: &Lua, f: impl FnOnce() -> LuaResult<R>) -> LuaResult<R> {
+    let Some(limit) = lua.app_data_ref::<LuaLimits>().map(|l| l.instructions) else {
+        return f();
+    };
+    lua.set_hook(LuaHookTriggers::new().every_nth_instruction(limit), move |_, _| {
+        Err(LuaError::external(InstructionLimit(limit)))
+    });
+    let res = f();
+    lua.remove_hook();
+    res
+}
+
+/// Which limit the error is about, if it is about one
+fn broken_limit(err: &LuaError) -> Option<String> {
+    match err {
+        LuaError::MemoryError(_) => Some("ran out of memory".to_string()),
+        LuaError::CallbackError { cause, .. } => broken_limit(cause),
+        e => e.downcast_ref::<InstructionLimit>().map(|l| l.to_string()),
+    }
+}
+
+/// Splits the error from the traceback Lua put under it
+fn split_traceback(err: &LuaError) -> (String, String) {
+    match err {
+        LuaError::CallbackError { traceback, cause } => (split_traceback(cause).0, traceback.clone()),
+        LuaError::RuntimeError(msg) => match msg.split_once("\nstack traceback:") {
+            Some((msg, trace)) => (msg.to_string(), format!("stack traceback:{}", trace)),
+            None => (msg.clone(), String::new()),
+        },
+        e => (e.to_string(), String::new()),
+    }
+}
+
+/// Turns an error from a callback into the error the server reports
+fn script_error(callback_name: &str, err: LuaError) -> ServerError {
+    match broken_limit(&err) {
+        Some(limit) => ServerError::ScriptLimit(callback_name.to_string(), limit),
+        None => {
+            let (error, traceback) = split_traceback(&err);
+            ServerError::ScriptError(callback_name.to_string(), error, traceback)
+        }
+    }
+}
+
+/// The sender of chat messages that come from the game itself
+pub const SYSTEM_SENDER: &str = "SYSTEM";
+
 pub struct StateFrame {
     players: HashMap<String, LuaPlayer>,
+    phase: Phase,
+    next_timer_id: TimerId,
     pub outbound: Vec<Message>,
+    pub scheduled: Vec<Timer>,
+    pub cancelled: Vec<TimerId>,
+    /// Seq of the message that set off the callback, mistakes in this frame are logged after it
+    pub after: Option<u64>,
 }
 
 impl StateFrame {
-    pub fn make(value: &YapnetState) -> Self {
+    pub fn make(value: &YapnetState, after: Option<u64>) -> Self {
         let mut players = HashMap::new();
         let outbound = vec![];
 
-        for u in value.users.iter() {
-            players.insert(u.0.clone(), u.into());
+        for (name, player) in value.game.players.iter() {
+            players.insert(name.clone(), LuaPlayer::new(name, player));
         }
 
-        Self { players, outbound }
+        Self {
+            players,
+            phase: value.game.phase.clone(),
+            next_timer_id: value.timers.next_id(),
+            outbound,
+            scheduled: vec![],
+            cancelled: vec![],
+            after,
+        }
+    }
+
+    pub fn schedule(&mut self, seconds: f64, name: String, payload: serde_json::Value) -> LuaResult<TimerId> {
+        let fire_at = Some(seconds * 1000.0)
+            .filter(|ms| ms.is_finite() && *ms >= 0.0 && *ms < i64::MAX as f64)
+            .and_then(|ms| TimeDelta::try_milliseconds(ms as i64))
+            .and_then(|d| Utc::now().checked_add_signed(d))
+            .ok_or_else(|| LuaError::runtime(format!("Cannot schedule a timer in {} seconds", seconds)))?;
+        let id = self.next_timer_id;
+        self.next_timer_id += 1;
+        self.scheduled.push(Timer { id, name, payload, fire_at });
+        Ok(id)
+    }
+
+    pub fn cancel(&mut self, id: TimerId) {
+        self.cancelled.push(id);
+    }
+
+    fn player(&self, user: &String) -> LuaResult<&LuaPlayer> {
+        self.players
+            .get(user)
+            .ok_or_else(|| LuaError::runtime(format!("{} is not a player", user)))
+    }
+
+    fn player_mut(&mut self, user: &String) -> LuaResult<&mut LuaPlayer> {
+        self.players
+            .get_mut(user)
+            .ok_or_else(|| LuaError::runtime(format!("{} is not a player", user)))
+    }
+
+    /// Names of all players, sorted
+    pub fn player_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.players.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// The role of the player, if it has one
+    pub fn role(&self, user: &String) -> LuaResult<Option<String>> {
+        Ok(Some(self.player(user)?.role.clone()).filter(|r| !r.is_empty()))
+    }
+
+    /// The groups of the player, sorted
+    pub fn groups(&self, user: &String) -> LuaResult<Vec<String>> {
+        Ok(self.player(user)?.groups.iter().cloned().collect())
+    }
+
+    pub fn phase(&self) -> &Phase {
+        &self.phase
+    }
+
+    /// Gives a player a role, revealing it to whoever should know
+    pub fn set_role(&mut self, user: String, role: String) -> LuaResult<()> {
+        self.player_mut(&user)?.role = role.clone();
+        self.outbound.push(RoleReveal { to: user.clone(), user, role }.into_message());
+        Ok(())
+    }
+
+    /// Picks the action the player does in this phase, none clears it
+    pub fn select_action(&mut self, user: String, action_id: Option<String>) -> LuaResult<()> {
+        self.player_mut(&user)?.current_action = action_id.clone().unwrap_or_default();
+        self.outbound.push(ActionSelected { player: user, action_id, args: vec![] }.into_message());
+        Ok(())
+    }
+
+    /// Moves the game to another phase
+    pub fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase.clone();
+        self.outbound.push(PhaseChange { phase }.into_message());
+    }
+
+    pub fn kill(&mut self, user: String) {
+        if let Some(player) = self.players.get_mut(&user) {
+            player.alive = false;
+        }
+        self.outbound.push(KillReveal { user, role: None }.into_message());
+    }
+
+    /// Starts a vote in a chat, ending after the given amount of seconds
+    pub fn start_vote(&mut self, chat_id: String, subject: String, seconds: i64) -> LuaResult<()> {
+        let end_time = TimeDelta::try_seconds(seconds)
+            .and_then(|d| Utc::now().checked_add_signed(d))
+            .ok_or_else(|| LuaError::runtime(format!("A vote cannot last {} seconds", seconds)))?;
+        self.outbound.push(
+            VoteStart {
+                chat_id,
+                subject,
+                end_time,
+            }
+            .into_message(),
+        );
+        Ok(())
+    }
+
+    /// Opens a chat, who can use it is either a target like `allowed` in the chats table,
+    /// or a table with the same rules as a chat in the chats table
+    pub fn create_chat(&mut self, name: String, allowed: LuaValue, phases: Vec<String>) -> LuaResult<()> {
+        let perm = match allowed {
+            LuaValue::Nil => Perms::new(),
+            LuaValue::Table(t) => parse_chat_perms(&t)?,
+            LuaValue::String(s) => Perms::wrap_vec(parse_target(s.to_str()?, 3).into_iter().collect()),
+            v => return Err(LuaError::runtime(format!("Cannot make chat rules from {}", v.type_name()))),
+        };
+        let chat = ChatSetup {
+            name,
+            perm,
+            phases: phases.into_iter().map(Phase::from).collect(),
+        };
+        self.outbound.push(ChatCreated { chat }.into_message());
+        Ok(())
+    }
+
+    pub fn delete_chat(&mut self, name: String) {
+        self.outbound.push(ChatDeleted { name }.into_message());
+    }
+
+    /// Says something in a chat as the game
+    pub fn send_chat(&mut self, chat_target: String, chat_content: String) {
+        self.outbound.push(
+            ChatSent {
+                chat_sender: SYSTEM_SENDER.to_string(),
+                chat_target,
+                chat_content,
+            }
+            .into_message(),
+        );
+    }
+
+    /// Tells something to a single player
+    pub fn send_player(&mut self, to: String, content: String) -> LuaResult<()> {
+        self.player(&to)?;
+        self.outbound.push(SystemMessage { to, content }.into_message());
+        Ok(())
+    }
+
+    /// Changes the groups of a player, the frame keeps track of the changes made so far
+    pub fn change_groups(&mut self, user: String, f: impl FnOnce(&mut BTreeSet<String>)) -> LuaResult<()> {
+        let player = self.player_mut(&user)?;
+        f(&mut player.groups);
+        let groups = player.groups.iter().cloned().collect();
+        self.outbound.push(
+            GroupsChanged {
+                user,
+                groups,
+                chats: vec![],
+            }
+            .into_message(),
+        );
+        Ok(())
+    }
+}
+
+/// Hands a JSON value to Lua as a table
+pub struct JsonArg(pub serde_json::Value);
+
+impl<'lua> IntoLua<'lua> for JsonArg {
+    fn into_lua(self, lua: &'lua Lua) -> LuaResult<LuaValue<'lua>> {
+        lua.to_value(&self.0)
+    }
+}
+
+/// What a callback decided about a message from a player.
+/// Returning nothing or true lets it through, false rejects it with an optional reason
+/// and a string replaces the content.
+#[derive(Debug, Default, PartialEq)]
+pub enum Verdict {
+    #[default]
+    Accept,
+    Reject(Option<String>),
+    Rewrite(String),
+}
+
+impl<'lua> FromLuaMulti<'lua> for Verdict {
+    fn from_lua_multi(values: LuaMultiValue<'lua>, lua: &'lua Lua) -> LuaResult<Self> {
+        let (verdict, reason): (LuaValue, Option<String>) = FromLuaMulti::from_lua_multi(values, lua)?;
+        match verdict {
+            LuaValue::Nil | LuaValue::Boolean(true) => Ok(Verdict::Accept),
+            LuaValue::Boolean(false) => Ok(Verdict::Reject(reason)),
+            LuaValue::String(content) => Ok(Verdict::Rewrite(content.to_str()?.to_string())),
+            other => Err(LuaError::FromLuaConversionError {
+                from: other.type_name(),
+                to: "verdict",
+                message: Some("expected nothing, a boolean or a string".to_string()),
+            }),
+        }
     }
 }
 
@@ -126,10 +545,53 @@ impl LuaUserData for StateFrame {
             this.outbound.push(msg);
             Ok(())
         });
+
+        // Gets the name of the current phase
+        methods.add_method("get_phase", |_, this, ()| Ok(this.phase.to_string()));
+
+        // Moves the game to another phase
+        methods.add_method_mut("set_phase", |_, this, phase: String| {
+            this.set_phase(phase.into());
+            Ok(())
+        });
+
+        // Gives a player a role, revealing it to whoever should know
+        methods.add_method_mut("set_role", |_, this, (user, role): (String, String)| this.set_role(user, role));
+
+        // Adds a player to a group
+        methods.add_method_mut("add_group", |_, this, (user, group): (String, String)| {
+            this.change_groups(user, |groups| {
+                groups.insert(group);
+            })
+        });
+
+        // Removes a player from a group
+        methods.add_method_mut("remove_group", |_, this, (user, group): (String, String)| {
+            this.change_groups(user, |groups| {
+                groups.remove(&group);
+            })
+        });
+
+        // Kills a player
+        methods.add_method_mut("kill", |_, this, user: String| {
+            this.kill(user);
+            Ok(())
+        });
+
+        // Starts a vote in a chat, ending after the given amount of seconds
+        methods.add_method_mut(
+            "start_vote",
+            |_, this, (chat_id, subject, seconds): (String, String, i64)| this.start_vote(chat_id, subject, seconds),
+        );
     }
 }
 
 impl LuaState {
+    /// The limits the runtime was made with, the defaults if it has none
+    pub fn limits(&self) -> LuaLimits {
+        self.lua.app_data_ref::<LuaLimits>().map(|l| *l).unwrap_or_default()
+    }
+
     #[inline]
     pub fn get_setup_table<'t, 'lua>(&'lua self) -> LuaTable<'t>
     where
@@ -141,25 +603,35 @@ impl LuaState {
             .expect("The __game table should always be there")
     }
 
-    pub fn callback<'lua>(
+    /// Calls a callback from the __game table with the frame as the first argument.
+    /// A missing callback behaves like one that returned nothing.
+    /// The callback is aborted when it goes over the limits of the runtime.
+    pub fn callback<'lua, A: IntoLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
         &'lua self,
         callback_name: &'static str,
         frame: Arc<Mutex<StateFrame>>,
-        mut args: LuaMultiValue<'lua>,
-    ) {
-        match self.get_setup_table().get::<_, LuaFunction>(callback_name) {
+        args: A,
+    ) -> Result<R, ServerError> {
+        let call_res: Result<R, LuaError> = match self.get_setup_table().get::<_, LuaFunction>(callback_name) {
             Ok(oc) => {
-                eprintln!("callback");
-                let call_res: Result<(), LuaError> = self.lua.scope(|scope| {
-                    let frame_s = scope.create_userdata(frame.clone())?;
-                    args.push_front(frame_s.into_lua(&self.lua)?);
-                    oc.call(args)
+                // The yapi functions reach the frame through the app data
+                self.lua.set_app_data(frame.clone());
+                let res = run_limited(&self.lua, || {
+                    self.lua.scope(|scope| {
+                        let frame_s = scope.create_userdata(frame.clone())?;
+                        let mut args = args.into_lua_multi(&self.lua)?;
+                        args.push_front(frame_s.into_lua(&self.lua)?);
+                        oc.call(args)
+                    })
                 });
-                if let Err(err) = call_res {
-                    eprintln!("Error in callback '{}'\n{}", callback_name, err)
-                }
+                self.lua.remove_app_data::<Arc<Mutex<StateFrame>>>();
+                res
+            }
+            Err(e) => {
+                eprintln!("WARNING: {}", e);
+                R::from_lua_multi(LuaMultiValue::new(), &self.lua)
             }
-            Err(e) => eprintln!("WARNING: {}", e),
         };
+        call_res.map_err(|err| script_error(callback_name, err))
     }
 }
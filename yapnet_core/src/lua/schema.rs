@@ -0,0 +1,477 @@
+// Copyright 2025 Jakub Stachurski
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::fmt;
+
+use mlua::prelude::*;
+
+use crate::protocol::Phase;
+
+/// The callbacks the server calls, see [crate::state::YapnetState]
+pub const CALLBACKS: &[&str] = &[
+    "assign_roles",
+    "on_action",
+    "on_back",
+    "on_chat",
+    "on_join",
+    "on_leave",
+    "on_phase_enter",
+    "on_phase_exit",
+    "on_timer",
+    "on_vote",
+    "on_vote_result",
+];
+
+const ARG_TYPES: &[&str] = &["player", "chat", "text"];
+
+/// The keys of a chat that say who can use it
+const CHAT_RULES: &[&str] = &["allowed", "read", "write", "rules"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// The game cannot be built
+    Error,
+    /// The game can be built, but probably not as the script meant
+    Warning,
+}
+
+/// Something off in the game table, with the Lua path to it
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.path, self.message)
+    }
+}
+
+/// Every problem found in the game table
+#[derive(Clone, Debug, Default)]
+pub struct SchemaReport {
+    pub problems: Vec<Problem>,
+}
+
+impl SchemaReport {
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| p.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Problem> {
+        self.problems.iter().filter(|p| p.severity == Severity::Warning)
+    }
+
+    fn error(&mut self, path: &str, message: impl Into<String>) {
+        self.push(Severity::Error, path, message.into());
+    }
+
+    fn warn(&mut self, path: &str, message: impl Into<String>) {
+        self.push(Severity::Warning, path, message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: &str, message: String) {
+        self.problems.push(Problem {
+            severity,
+            path: path.to_string(),
+            message,
+        });
+    }
+}
+
+impl fmt::Display for SchemaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The game script has {} problem(s):", self.problems.len())?;
+        for problem in self.problems.iter() {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SchemaReport {}
+
+/// Checks the `__game` global of the runtime before the game is built from it,
+/// so a broken script is reported in one go instead of failing on the first thing that is off.
+pub fn validate(lua: &Lua) -> SchemaReport {
+    let mut report = SchemaReport::default();
+    let game = lua.globals().get::<_, LuaValue>("__game").unwrap_or(LuaValue::Nil);
+    let Some(game) = table(&mut report, "__game", &game) else {
+        return report;
+    };
+
+    let phases = strings(&mut report, "__game.phases", &field(game, "phases"));
+    let roles = match field(game, "roles") {
+        LuaValue::Table(t) => keys(&t),
+        _ => vec![],
+    };
+
+    for pair in game.clone().pairs::<LuaValue, LuaValue>() {
+        let Ok((key, value)) = pair else { continue };
+        let Some(key) = key_name(&mut report, "__game", &key) else { continue };
+        let path = format!("__game.{}", key);
+        match key.as_str() {
+            "chats" => validate_chats(&mut report, &path, &value, &phases),
+            "roles" => validate_roles(&mut report, &path, &value),
+            "actions" => validate_actions(&mut report, &path, &value, &phases, &roles),
+            // The script keeps its own settings, the server only checks they are a table
+            "settings" => {
+                table(&mut report, &path, &value);
+            }
+            "phases" => {}
+            k if CALLBACKS.contains(&k) => {
+                if !matches!(value, LuaValue::Function(_)) {
+                    report.error(&path, format!("callbacks have to be functions, got {}", value.type_name()));
+                }
+            }
+            _ => report.warn(&path, "unknown key, the server does not use it"),
+        }
+    }
+
+    if matches!(field(game, "chats"), LuaValue::Nil) {
+        report.error("__game.chats", "missing, the game needs at least one chat");
+    }
+    report
+}
+
+fn validate_chats(report: &mut SchemaReport, path: &str, value: &LuaValue, phases: &[String]) {
+    let Some(chats) = table(report, path, value) else { return };
+    for pair in chats.clone().pairs::<LuaValue, LuaValue>() {
+        let Ok((name, chat)) = pair else { continue };
+        let Some(name) = key_name(report, path, &name) else { continue };
+        let path = format!("{}.{}", path, name);
+        let Some(chat) = table(report, &path, &chat) else { continue };
+        for (key, value) in entries(report, &path, chat) {
+            let path = format!("{}.{}", path, key);
+            match key.as_str() {
+                "allowed" => match &value {
+                    LuaValue::String(s) => validate_target(report, &path, &s.to_string_lossy()),
+                    v => report.error(&path, format!("expected a string, got {}", v.type_name())),
+                },
+                "read" | "write" => {
+                    for (i, target) in strings(report, &path, &value).iter().enumerate() {
+                        validate_target(report, &format!("{}[{}]", path, i + 1), target);
+                    }
+                }
+                "rules" => validate_rules(report, &path, &value),
+                "phases" => check_phases(report, &path, &value, phases),
+                _ => report.warn(&path, "unknown key, the server does not use it"),
+            }
+        }
+        if CHAT_RULES.iter().all(|k| matches!(field(chat, k), LuaValue::Nil)) {
+            report.warn(&path, format!("has none of {}, nobody can use the chat", CHAT_RULES.join(", ")));
+        }
+    }
+}
+
+/// Targets are "any", "none", "user:name" or "group:name", a bare name is taken as a group
+fn validate_target(report: &mut SchemaReport, path: &str, target: &str) {
+    match target.split_once(':') {
+        Some(("user" | "group", "")) => report.error(path, "the name is empty"),
+        Some(("user" | "group", _)) => {}
+        _ => match target {
+            "any" | "all" | "none" => {}
+            "" => report.error(path, "the target is empty"),
+            t => report.warn(
+                path,
+                format!("'{}' is taken as the group '{}', write 'group:{}' to make that clear", t, t, t),
+            ),
+        },
+    }
+}
+
+/// Each rule is `{ user = name | group = name | any = true, rw = 1 | 2 | 3 }`
+fn validate_rules(report: &mut SchemaReport, path: &str, value: &LuaValue) {
+    let Some(rules) = table(report, path, value) else { return };
+    for (i, rule) in rules.clone().sequence_values::<LuaValue>().enumerate() {
+        let path = format!("{}[{}]", path, i + 1);
+        let Ok(rule) = rule else { continue };
+        let Some(rule) = table(report, &path, &rule) else { continue };
+        let mut targets = 0;
+        for (key, value) in entries(report, &path, rule) {
+            let path = format!("{}.{}", path, key);
+            match (key.as_str(), &value) {
+                ("user" | "group", LuaValue::String(s)) if !s.as_bytes().is_empty() => targets += 1,
+                ("user" | "group", LuaValue::String(_)) => report.error(&path, "the name is empty"),
+                ("any", LuaValue::Boolean(true)) => targets += 1,
+                ("any", LuaValue::Boolean(false)) => {}
+                ("rw", LuaValue::Integer(1..=3)) => {}
+                ("rw", v) => report.error(&path, format!("expected 1 (read), 2 (write) or 3 (both), got {}", lua_value(v))),
+                ("user" | "group" | "any", v) => report.error(&path, format!("unexpected {}", v.type_name())),
+                _ => report.warn(&path, "unknown key, the server does not use it"),
+            }
+        }
+        if targets != 1 {
+            report.error(&path, "a rule needs exactly one of user, group or any");
+        }
+    }
+}
+
+/// Short form of a value for the report
+fn lua_value(value: &LuaValue) -> String {
+    match value {
+        LuaValue::Integer(i) => i.to_string(),
+        LuaValue::Number(n) => n.to_string(),
+        LuaValue::String(s) => format!("'{}'", s.to_string_lossy()),
+        v => v.type_name().to_string(),
+    }
+}
+
+fn validate_roles(report: &mut SchemaReport, path: &str, value: &LuaValue) {
+    let Some(roles) = table(report, path, value) else { return };
+    for pair in roles.clone().pairs::<LuaValue, LuaValue>() {
+        let Ok((name, role)) = pair else { continue };
+        let Some(name) = key_name(report, path, &name) else { continue };
+        let path = format!("{}.{}", path, name);
+        let Some(role) = table(report, &path, &role) else { continue };
+        for (key, value) in entries(report, &path, role) {
+            let path = format!("{}.{}", path, key);
+            match key.as_str() {
+                "known_by" => {
+                    strings(report, &path, &value);
+                }
+                "reveal_on_death" => {
+                    if !matches!(value, LuaValue::Boolean(_)) {
+                        report.error(&path, format!("expected a boolean, got {}", value.type_name()));
+                    }
+                }
+                _ => report.warn(&path, "unknown key, the server does not use it"),
+            }
+        }
+    }
+}
+
+fn validate_actions(report: &mut SchemaReport, path: &str, value: &LuaValue, phases: &[String], roles: &[String]) {
+    let Some(actions) = table(report, path, value) else { return };
+    for pair in actions.clone().pairs::<LuaValue, LuaValue>() {
+        let Ok((name, action)) = pair else { continue };
+        let Some(name) = key_name(report, path, &name) else { continue };
+        let path = format!("{}.{}", path, name);
+        let Some(action) = table(report, &path, &action) else { continue };
+        for (key, value) in entries(report, &path, action) {
+            let path = format!("{}.{}", path, key);
+            match key.as_str() {
+                "args" => {
+                    for (i, arg) in strings(report, &path, &value).iter().enumerate() {
+                        if !ARG_TYPES.contains(&arg.as_str()) {
+                            report.error(
+                                &format!("{}[{}]", path, i + 1),
+                                format!("unknown argument type '{}', expected one of {}", arg, ARG_TYPES.join(", ")),
+                            );
+                        }
+                    }
+                }
+                "roles" => {
+                    for (i, role) in strings(report, &path, &value).iter().enumerate() {
+                        if !roles.contains(role) {
+                            report.warn(&format!("{}[{}]", path, i + 1), format!("the role '{}' is not declared", role));
+                        }
+                    }
+                }
+                "phases" => check_phases(report, &path, &value, phases),
+                _ => report.warn(&path, "unknown key, the server does not use it"),
+            }
+        }
+    }
+}
+
+/// Phases have to be built in or declared in `__game.phases`
+fn check_phases(report: &mut SchemaReport, path: &str, value: &LuaValue, declared: &[String]) {
+    for (i, phase) in strings(report, path, value).iter().enumerate() {
+        if matches!(Phase::from(phase.clone()), Phase::Custom(_)) && !declared.contains(phase) {
+            report.warn(&format!("{}[{}]", path, i + 1), format!("the phase '{}' is not declared", phase));
+        }
+    }
+}
+
+fn field<'lua>(table: &LuaTable<'lua>, key: &str) -> LuaValue<'lua> {
+    table.raw_get(key).unwrap_or(LuaValue::Nil)
+}
+
+fn table<'a, 'lua>(report: &mut SchemaReport, path: &str, value: &'a LuaValue<'lua>) -> Option<&'a LuaTable<'lua>> {
+    match value {
+        LuaValue::Table(t) => Some(t),
+        v => {
+            report.error(path, format!("expected a table, got {}", v.type_name()));
+            None
+        }
+    }
+}
+
+fn key_name(report: &mut SchemaReport, path: &str, key: &LuaValue) -> Option<String> {
+    match key {
+        LuaValue::String(s) => Some(s.to_string_lossy().into_owned()),
+        k => {
+            report.error(path, format!("keys have to be names, got {}", k.type_name()));
+            None
+        }
+    }
+}
+
+fn keys(table: &LuaTable) -> Vec<String> {
+    table
+        .clone()
+        .pairs::<LuaValue, LuaValue>()
+        .filter_map(|pair| match pair {
+            Ok((LuaValue::String(s), _)) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The named fields of a table, other keys are reported
+fn entries<'lua>(report: &mut SchemaReport, path: &str, table: &LuaTable<'lua>) -> Vec<(String, LuaValue<'lua>)> {
+    table
+        .clone()
+        .pairs::<LuaValue, LuaValue>()
+        .filter_map(|pair| {
+            let (key, value) = pair.ok()?;
+            Some((key_name(report, path, &key)?, value))
+        })
+        .collect()
+}
+
+/// A list of strings, missing counts as empty
+fn strings(report: &mut SchemaReport, path: &str, value: &LuaValue) -> Vec<String> {
+    let list = match value {
+        LuaValue::Nil => return vec![],
+        v => match table(report, path, v) {
+            Some(t) => t,
+            None => return vec![],
+        },
+    };
+    let mut out = vec![];
+    for (i, item) in list.clone().sequence_values::<LuaValue>().enumerate() {
+        match item {
+            Ok(LuaValue::String(s)) => out.push(s.to_string_lossy().into_owned()),
+            Ok(v) => report.error(&format!("{}[{}]", path, i + 1), format!("expected a string, got {}", v.type_name())),
+            Err(e) => report.error(&format!("{}[{}]", path, i + 1), e.to_string()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(script: &str) -> SchemaReport {
+        let lua = Lua::new();
+        lua.load(script).exec().unwrap();
+        validate(&lua)
+    }
+
+    fn paths(report: &SchemaReport, severity: Severity) -> Vec<&str> {
+        report
+            .problems
+            .iter()
+            .filter(|p| p.severity == severity)
+            .map(|p| p.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn a_good_game_has_no_problems() {
+        let report = check(
+            r#"__game = {
+                chats = { town = { allowed = "any", phases = { "day", "dusk" } }, mafia = { allowed = "group:mafia" } },
+                phases = { "dusk" },
+                roles = { mafia = { known_by = { "mafia" }, reveal_on_death = false } },
+                actions = { kill = { args = { "player" }, roles = { "mafia" }, phases = { "night" } } },
+                settings = { day_length = 120 },
+                on_chat = function () end,
+            }"#,
+        );
+        assert!(report.problems.is_empty(), "{}", report);
+    }
+
+    #[test]
+    fn a_missing_game_is_an_error() {
+        let report = check("");
+        assert_eq!(paths(&report, Severity::Error), vec!["__game"]);
+    }
+
+    #[test]
+    fn every_problem_is_reported_with_its_path() {
+        let report = check(
+            r#"__game = {
+                chats = { town = { allowed = 3 } },
+                actions = { kill = { args = { "player", "weapon" } } },
+                roles = { mafia = { reveal_on_death = "yes" } },
+                on_chat = "hello",
+            }"#,
+        );
+        let mut errors = paths(&report, Severity::Error);
+        errors.sort();
+        assert_eq!(
+            errors,
+            vec![
+                "__game.actions.kill.args[2]",
+                "__game.chats.town.allowed",
+                "__game.on_chat",
+                "__game.roles.mafia.reveal_on_death",
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_keys_and_guesses_are_warnings() {
+        let report = check(
+            r#"__game = {
+                chats = { town = { allowed = "mafia", colour = "red" } },
+                actions = { kill = { roles = { "mafia" }, phases = { "dusk" } } },
+                on_chta = function () end,
+            }"#,
+        );
+        assert!(!report.has_errors(), "{}", report);
+        let mut warnings = paths(&report, Severity::Warning);
+        warnings.sort();
+        assert_eq!(
+            warnings,
+            vec![
+                "__game.actions.kill.phases[1]",
+                "__game.actions.kill.roles[1]",
+                "__game.chats.town.allowed",
+                "__game.chats.town.colour",
+                "__game.on_chta",
+            ]
+        );
+    }
+
+    #[test]
+    fn chat_rules_are_checked() {
+        let report = check(
+            r#"__game = {
+                chats = {
+                    news = { read = { "any" }, write = { "user:" } },
+                    graveyard = { rules = { { user = "narrator", group = "dead" }, { group = "dead", rw = 5 }, { any = true } } },
+                    void = {},
+                },
+            }"#,
+        );
+        let mut errors = paths(&report, Severity::Error);
+        errors.sort();
+        assert_eq!(
+            errors,
+            vec!["__game.chats.graveyard.rules[1]", "__game.chats.graveyard.rules[2].rw", "__game.chats.news.write[1]"]
+        );
+        assert_eq!(paths(&report, Severity::Warning), vec!["__game.chats.void"]);
+    }
+}
@@ -12,12 +12,16 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use crate::models::user::User;
-use crate::protocol::Perm;
+use super::{run_limited, set_limits, LuaLimits, StateFrame};
+use crate::models::timer::TimerId;
+use crate::protocol::Phase;
+use crate::models::game::Player;
 use mlua::chunk;
 use mlua::prelude::*;
 use mlua::StdLib;
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 type RoleID = String;
 type ActionID = String;
@@ -26,59 +30,208 @@ pub fn init_lua_from_argv() -> Lua {
     let mut args = std::env::args();
     let _ = args.next();
     let file_name = args.next().expect("No file argument given");
-    init_lua(file_name.into())
+    init_lua(file_name.into(), LuaLimits::default())
 }
 
-pub fn init_lua<'table>(path: PathBuf) -> Lua {
+pub fn init_lua(path: PathBuf, limits: LuaLimits) -> Lua {
+    load_lua(&path, limits).expect("Loading the game script failed")
+}
+
+/// Makes a fresh runtime with the api and the limits, and runs the game script in it
+pub fn load_lua(path: &Path, limits: LuaLimits) -> LuaResult<Lua> {
     let opts = LuaOptions::new();
-    let lua = Lua::new_with(StdLib::ALL_SAFE, opts).expect("Creation of lua runtime failed");
+    let lua = Lua::new_with(StdLib::ALL_SAFE, opts)?;
 
     push_api(&lua);
+    set_limits(&lua, limits)?;
 
     {
-        let file = path.to_str().unwrap();
-        println!("Loading file: {}", file);
-        lua.load(chunk!(
-            __game = dofile($file)
-        ))
-        .exec()
-        .unwrap();
+        let file = path.to_string_lossy();
+        run_limited(&lua, || {
+            lua.load(chunk!(
+                __game = dofile($file)
+            ))
+            .exec()
+        })?;
     }
 
-    lua
+    Ok(lua)
 }
 
+/// A copy of a player handed to the scripts, setting a field changes the player through the frame
 #[derive(Clone)]
 pub struct LuaPlayer {
-    username: String,
-    role: RoleID,
-    groups: Vec<Perm>,
-    current_action: ActionID,
+    pub username: String,
+    pub role: RoleID,
+    pub groups: BTreeSet<String>,
+    pub online: bool,
+    pub alive: bool,
+    pub current_action: ActionID,
 }
 
-impl From<(&String, &User)> for LuaPlayer {
-    fn from(value: (&String, &User)) -> Self {
+impl LuaPlayer {
+    pub fn new(username: &str, player: &Player) -> Self {
         Self {
-            username: value.0.clone(),
-            role: "".to_string(),
-            groups: vec![],
-            current_action: "".to_string(),
+            username: username.to_string(),
+            role: player.role.clone().unwrap_or_default(),
+            groups: player.groups.clone(),
+            online: player.online,
+            alive: player.alive,
+            current_action: player.current_action.as_ref().map(|(id, _)| id.clone()).unwrap_or_default(),
         }
     }
 }
 
-impl LuaUserData for LuaPlayer {}
+/// Empty strings are nil on the Lua side
+fn non_empty(s: &str) -> Option<String> {
+    Some(s.to_string()).filter(|s| !s.is_empty())
+}
+
+fn read_only(field: &str) -> LuaError {
+    LuaError::runtime(format!("The {} of a player cannot be changed", field))
+}
+
+impl LuaUserData for LuaPlayer {
+    fn add_fields<'lua, F: LuaUserDataFields<'lua, Self>>(fields: &mut F) {
+        fields.add_field_method_get("username", |_, this| Ok(this.username.clone()));
+        fields.add_field_method_get("role", |_, this| Ok(non_empty(&this.role)));
+        fields.add_field_method_get("groups", |_, this| Ok(this.groups.iter().cloned().collect::<Vec<_>>()));
+        fields.add_field_method_get("online", |_, this| Ok(this.online));
+        fields.add_field_method_get("alive", |_, this| Ok(this.alive));
+        fields.add_field_method_get("current_action", |_, this| Ok(non_empty(&this.current_action)));
+
+        fields.add_field_method_set("role", |lua, this, role: String| {
+            with_frame(lua, |frame| frame.set_role(this.username.clone(), role.clone()))??;
+            this.role = role;
+            Ok(())
+        });
+        fields.add_field_method_set("groups", |lua, this, groups: Vec<String>| {
+            let groups: BTreeSet<String> = groups.into_iter().collect();
+            with_frame(lua, |frame| frame.change_groups(this.username.clone(), |g| *g = groups.clone()))??;
+            this.groups = groups;
+            Ok(())
+        });
+        fields.add_field_method_set("alive", |lua, this, alive: bool| {
+            match (this.alive, alive) {
+                (true, false) => with_frame(lua, |frame| frame.kill(this.username.clone()))?,
+                (false, true) => return Err(LuaError::runtime("Dead players cannot be brought back")),
+                _ => {}
+            }
+            this.alive = alive;
+            Ok(())
+        });
+        fields.add_field_method_set("username", |_, _, _: LuaValue| Err::<(), _>(read_only("username")));
+        fields.add_field_method_set("online", |_, _, _: LuaValue| Err::<(), _>(read_only("online")));
+        fields.add_field_method_set("current_action", |lua, this, action_id: Option<String>| {
+            with_frame(lua, |frame| frame.select_action(this.username.clone(), action_id.clone()))??;
+            this.current_action = action_id.unwrap_or_default();
+            Ok(())
+        });
+    }
+}
+
+/// Runs the function on the frame of the callback that is currently running
+fn with_frame<T>(lua: &Lua, f: impl FnOnce(&mut StateFrame) -> T) -> LuaResult<T> {
+    let frame = lua
+        .app_data_ref::<Arc<Mutex<StateFrame>>>()
+        .ok_or_else(|| LuaError::runtime("This function can only be used inside of a callback"))?;
+    let mut frame = frame.lock().map_err(|e| LuaError::runtime(e.to_string()))?;
+    Ok(f(&mut frame))
+}
+
+/// Calls `on_timer(frame, name, payload, id)` after the given amount of seconds, returns the timer id
+fn schedule(lua: &Lua, (seconds, name, payload): (f64, String, LuaValue)) -> LuaResult<TimerId> {
+    let payload: serde_json::Value = lua.from_value(payload)?;
+    with_frame(lua, |frame| frame.schedule(seconds, name, payload))?
+}
+
+/// Cancels a scheduled timer
+fn cancel(lua: &Lua, id: TimerId) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.cancel(id))
+}
+
+/// Returns the names of all players, sorted
+fn players(lua: &Lua, (): ()) -> LuaResult<Vec<String>> {
+    with_frame(lua, |frame| frame.player_names())
+}
+
+/// Returns the role of a player, nil if it has none
+fn get_role(lua: &Lua, user: String) -> LuaResult<Option<String>> {
+    with_frame(lua, |frame| frame.role(&user))?
+}
+
+/// Gives a player a role, revealing it to whoever should know
+fn set_role(lua: &Lua, (user, role): (String, String)) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.set_role(user, role))?
+}
 
-fn yn_api_test(_lua: &Lua, arg: String) -> LuaResult<()> {
-    println!("Hello {}", arg);
-    Ok(())
+/// Returns the groups of a player, sorted
+fn get_groups(lua: &Lua, user: String) -> LuaResult<Vec<String>> {
+    with_frame(lua, |frame| frame.groups(&user))?
+}
+
+/// Adds a player to a group
+fn add_group(lua: &Lua, (user, group): (String, String)) -> LuaResult<()> {
+    with_frame(lua, |frame| {
+        frame.change_groups(user, |groups| {
+            groups.insert(group);
+        })
+    })?
+}
+
+/// Removes a player from a group
+fn remove_group(lua: &Lua, (user, group): (String, String)) -> LuaResult<()> {
+    with_frame(lua, |frame| {
+        frame.change_groups(user, |groups| {
+            groups.remove(&group);
+        })
+    })?
+}
+
+/// Opens a chat, allowed is a target like "any" or "group:dead", or a table of rules like in the chats table.
+/// The phases limit when it can be written in.
+fn create_chat<'lua>(
+    lua: &'lua Lua,
+    (name, allowed, phases): (String, LuaValue<'lua>, Option<Vec<String>>),
+) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.create_chat(name, allowed, phases.unwrap_or_default()))?
+}
+
+/// Closes a chat for good
+fn delete_chat(lua: &Lua, name: String) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.delete_chat(name))
+}
+
+/// Says something in a chat as the game
+fn send_chat(lua: &Lua, (chat, content): (String, String)) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.send_chat(chat, content))
+}
+
+/// Tells something to a single player
+fn send_player(lua: &Lua, (player, content): (String, String)) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.send_player(player, content))?
+}
+
+/// Starts a vote in a chat, ending after the given amount of seconds
+fn start_vote(lua: &Lua, (chat, subject, seconds): (String, String, i64)) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.start_vote(chat, subject, seconds))?
+}
+
+/// Ends the game by moving it to the ended phase
+fn end_game(lua: &Lua, (): ()) -> LuaResult<()> {
+    with_frame(lua, |frame| frame.set_phase(Phase::Ended))
+}
+
+/// Returns the name of the current phase
+fn get_phase(lua: &Lua, (): ()) -> LuaResult<String> {
+    with_frame(lua, |frame| frame.phase().to_string())
 }
 
 macro_rules! push_fns {
     ($lua:ident, $table:ident; $($fn:ident),*) => (
         $(
         $table.set(stringify!($fn), $lua.create_function($fn).expect(format!("Function  {} not lua-compatible", stringify!($fn)).as_str())).expect("Pushing onto api table failed");
-        ),*
+        )*
     )
 }
 
@@ -86,9 +239,240 @@ pub(super) fn push_api(l: &Lua) {
     let yn_api_table = l.create_table().unwrap();
 
     push_fns!(l, yn_api_table;
-        yn_api_test
+        schedule,
+        cancel,
+        players,
+        get_role,
+        set_role,
+        get_groups,
+        add_group,
+        remove_group,
+        create_chat,
+        delete_chat,
+        send_chat,
+        send_player,
+        start_vote,
+        end_game,
+        get_phase
     );
 
     // Push the final table into
     l.globals().set("yapi", yn_api_table).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lua::state_init;
+    use crate::prelude::*;
+    use crate::state::{ResponseView, YapnetState};
+
+    /// Runs the snippet when the game enters the day, with alice and bob playing
+    fn run(snippet: &str) -> (YapnetState, ResponseView) {
+        let lua = Lua::new();
+        push_api(&lua);
+        lua.load(format!(
+            r#"__game = {{
+                chats = {{ town = {{ allowed = "any" }} }},
+                roles = {{ mafia = {{}}, villager = {{}} }},
+                actions = {{ inspect = {{ args = {{ "player" }} }} }},
+                on_phase_enter = function (frame, phase)
+                    if phase == "day" then {} end
+                end,
+            }}"#,
+            snippet
+        ))
+        .exec()
+        .unwrap();
+
+        let mut state = state_init(lua).unwrap();
+        for name in ["alice", "bob"] {
+            state.new_user(&name.to_string()).unwrap();
+        }
+        let view = state.set_phase(Phase::Day).unwrap();
+        (state, view)
+    }
+
+    fn result<T: for<'lua> FromLua<'lua>>(state: &YapnetState) -> T {
+        state.lua_state.as_ref().unwrap().lua.globals().get("result").unwrap()
+    }
+
+    fn sent<T: TryFrom<MessageData>>(view: &ResponseView) -> Vec<T> {
+        view.iter().filter_map(|(_, m)| m.data.clone().try_into().ok()).collect()
+    }
+
+    #[test]
+    fn players_are_listed_sorted() {
+        let (state, _) = run("result = yapi.players()");
+        assert_eq!(result::<Vec<String>>(&state), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn roles_can_be_set_and_read() {
+        let (state, _) = run(r#"yapi.set_role("alice", "mafia") result = yapi.get_role("alice")"#);
+        assert_eq!(result::<String>(&state), "mafia");
+        assert_eq!(state.game.players["alice"].role.as_deref(), Some("mafia"));
+    }
+
+    #[test]
+    fn players_without_a_role_get_nil() {
+        let (state, _) = run(r#"result = yapi.get_role("bob") == nil"#);
+        assert!(result::<bool>(&state));
+    }
+
+    #[test]
+    fn unknown_players_are_an_error() {
+        let (state, _) = run(r#"result = pcall(yapi.get_role, "nobody")"#);
+        assert!(!result::<bool>(&state));
+    }
+
+    #[test]
+    fn groups_can_be_changed_and_read() {
+        let (state, _) = run(
+            r#"yapi.add_group("bob", "mafia")
+               yapi.add_group("bob", "traitor")
+               yapi.remove_group("bob", "traitor")
+               result = yapi.get_groups("bob")"#,
+        );
+        assert_eq!(result::<Vec<String>>(&state), vec!["mafia"]);
+        assert!(state.game.players["bob"].groups.contains("mafia"));
+        assert!(!state.game.players["bob"].groups.contains("traitor"));
+    }
+
+    #[test]
+    fn chats_can_be_created() {
+        let (state, _) = run(r#"yapi.create_chat("graveyard", "dead", { "night" })"#);
+        let chat = &state.game.chats["graveyard"];
+        assert_eq!(chat.phases, vec![Phase::Night]);
+        assert!(chat.perms.check_group(&"dead".to_string()) & 2 != 0);
+    }
+
+    #[test]
+    fn chats_can_be_created_with_rules() {
+        let (state, _) = run(r#"yapi.create_chat("news", { read = { "any" }, write = { "user:alice" } })"#);
+        let chat = &state.game.chats["news"];
+        assert_eq!(chat.access(&"alice".to_string(), &state.game.players["alice"]), 3);
+        assert_eq!(chat.access(&"bob".to_string(), &state.game.players["bob"]), 1);
+    }
+
+    #[test]
+    fn chats_can_be_deleted() {
+        let (state, _) = run(r#"yapi.delete_chat("town")"#);
+        assert!(!state.game.chats.contains_key("town"));
+    }
+
+    #[test]
+    fn system_messages_go_to_the_chat() {
+        let (_, view) = run(r#"yapi.send_chat("town", "Good morning")"#);
+        let chats: Vec<ChatSent> = sent(&view);
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].chat_sender, crate::lua::SYSTEM_SENDER);
+        assert_eq!(chats[0].chat_content, "Good morning");
+    }
+
+    #[test]
+    fn system_messages_go_to_a_single_player() {
+        let (state, view) = run(r#"yapi.send_player("alice", "You feel watched")"#);
+        let (_, msg) = view
+            .iter()
+            .find(|(_, m)| matches!(m.data, MessageData::BodySystemMessage(_)))
+            .unwrap();
+        assert!(state.user_can_view(msg, &"alice".to_string()));
+        assert!(!state.user_can_view(msg, &"bob".to_string()));
+    }
+
+    #[test]
+    fn votes_can_be_started() {
+        let (state, view) = run(r#"yapi.start_vote("town", "Who is the mafia?", 60)"#);
+        assert!(state.game.votes.contains_key("town"));
+        assert_eq!(sent::<VoteStart>(&view)[0].subject, "Who is the mafia?");
+    }
+
+    #[test]
+    fn votes_that_never_end_are_an_error() {
+        let (state, view) = run(r#"result = pcall(yapi.start_vote, "town", "Who is the mafia?", math.maxinteger)"#);
+        assert!(!result::<bool>(&state));
+        assert!(!state.game.votes.contains_key("town"));
+        assert!(sent::<VoteStart>(&view).is_empty());
+    }
+
+    #[test]
+    fn timers_that_never_fire_are_an_error() {
+        let (state, _) = run(r#"
+            result = {
+                pcall(yapi.schedule, math.huge, "never", {}),
+                pcall(yapi.schedule, -1, "past", {}),
+                (pcall(yapi.schedule, 0 / 0, "nan", {})),
+            }
+        "#);
+        assert_eq!(result::<Vec<bool>>(&state), vec![false, false, false]);
+        assert!(state.timers.is_empty());
+    }
+
+    #[test]
+    fn the_game_can_be_ended() {
+        let (state, _) = run("yapi.end_game()");
+        assert_eq!(state.game.phase, Phase::Ended);
+    }
+
+    #[test]
+    fn the_phase_can_be_read() {
+        let (state, _) = run("result = yapi.get_phase()");
+        assert_eq!(result::<String>(&state), "day");
+    }
+
+    #[test]
+    fn player_fields_can_be_read() {
+        let (state, _) = run(
+            r#"local p = frame:get_player_info("bob")
+               result = { p.username == "bob", p.alive, p.online, p.role == nil, #p.groups == 0, p.current_action == nil }"#,
+        );
+        assert_eq!(result::<Vec<bool>>(&state), vec![true; 6]);
+    }
+
+    #[test]
+    fn player_fields_change_the_game() {
+        let (state, view) = run(
+            r#"local p = frame:get_player_info("alice")
+               p.role = "mafia"
+               p.groups = { "mafia" }
+               p.alive = false
+               result = { p.role == "mafia", p.groups[1] == "mafia", not p.alive }"#,
+        );
+        assert_eq!(result::<Vec<bool>>(&state), vec![true; 3]);
+
+        let alice = &state.game.players["alice"];
+        assert_eq!(alice.role.as_deref(), Some("mafia"));
+        assert!(alice.groups.contains("mafia"));
+        assert!(!alice.alive);
+        assert_eq!(sent::<KillReveal>(&view).len(), 1);
+    }
+
+    #[test]
+    fn the_current_action_can_be_picked_and_cleared() {
+        let (state, view) = run(
+            r#"frame:get_player_info("alice").current_action = "inspect"
+               local bob = frame:get_player_info("bob")
+               bob.current_action = "inspect"
+               bob.current_action = nil
+               result = { frame:get_player_info("alice").current_action == "inspect", bob.current_action == nil }"#,
+        );
+        assert_eq!(result::<Vec<bool>>(&state), vec![true; 2]);
+        assert_eq!(sent::<ActionSelected>(&view).len(), 3);
+        assert_eq!(state.game.players["alice"].current_action, Some(("inspect".to_string(), vec![])));
+        assert_eq!(state.game.players["bob"].current_action, None);
+    }
+
+    #[test]
+    fn read_only_player_fields_are_an_error() {
+        let (state, _) = run(
+            r#"local p = frame:get_player_info("bob")
+               result = { pcall(function () p.username = "eve" end),
+                          pcall(function () p.online = false end),
+                          (pcall(function () p.alive = false p.alive = true end)) }"#,
+        );
+        assert_eq!(result::<Vec<bool>>(&state), vec![false; 3]);
+        assert!(state.game.players.contains_key("bob"));
+        assert!(state.game.players["bob"].online);
+    }
+}
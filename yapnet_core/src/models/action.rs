@@ -0,0 +1,50 @@
+// Copyright 2025 Jakub Stachurski
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use std::collections::BTreeMap;
+
+use crate::protocol::{ActionArg, ActionId, ActionInfo, Phase, RoleId};
+
+/// Actions declared by the game script, ordered by id so the lists sent to players are stable
+pub type Actions = BTreeMap<ActionId, ActionDef>;
+
+pub struct ActionDef {
+    pub args: Vec<ActionArg>,
+    /// Roles that can perform the action, everyone if empty
+    pub roles: Vec<RoleId>,
+    /// Phases in which the action can be performed, any phase if empty
+    pub phases: Vec<Phase>,
+}
+
+impl ActionDef {
+    pub fn new(args: Vec<ActionArg>, roles: Vec<RoleId>, phases: Vec<Phase>) -> Self {
+        Self {
+            args,
+            roles,
+            phases,
+        }
+    }
+
+    pub fn allows(&self, role: Option<&RoleId>, phase: &Phase) -> bool {
+        (self.roles.is_empty() || role.is_some_and(|r| self.roles.contains(r)))
+            && (self.phases.is_empty() || self.phases.contains(phase))
+    }
+
+    pub fn info(&self, id: &ActionId) -> ActionInfo {
+        ActionInfo {
+            id: id.clone(),
+            args: self.args.clone(),
+        }
+    }
+}
@@ -12,15 +12,19 @@
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
 
-use super::user::User;
-use crate::protocol::Perms;
+use super::game::Player;
+use crate::protocol::{Perms, Phase};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 pub type Chats = HashMap<String, Chat>;
 pub type MessageRef = usize;
 
+#[derive(Serialize, Deserialize)]
 pub struct Chat {
     pub perms: Perms,
+    /// Phases in which the chat can be written in, any phase if empty
+    pub phases: Vec<Phase>,
     pub messages: Vec<MessageRef>,
 }
 
@@ -28,15 +32,25 @@ impl Chat {
     pub fn new(perms: Perms) -> Self {
         Self {
             perms,
+            phases: vec![],
             messages: vec![],
         }
     }
-    pub fn can_write(&self, _: &User) -> bool {
-        // Todo: Check permissions
-        true
+    pub fn is_open(&self, phase: &Phase) -> bool {
+        self.phases.is_empty() || self.phases.contains(phase)
     }
-    pub fn can_read(&self, _: &User) -> bool {
-        // Todo: Check permissions
-        true
+    /// Returns the rw bits the user has in this chat, 1: read, 2: write
+    pub fn access(&self, username: &String, player: &Player) -> u8 {
+        player.groups
+            .iter()
+            .fold(self.perms.check_player(username), |rw, group| {
+                rw | self.perms.check_group(group)
+            })
+    }
+    pub fn can_write(&self, username: &String, player: &Player) -> bool {
+        self.access(username, player) & 2 != 0
+    }
+    pub fn can_read(&self, username: &String, player: &Player) -> bool {
+        self.access(username, player) & 1 != 0
     }
 }
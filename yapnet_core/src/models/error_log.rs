@@ -0,0 +1,89 @@
+// Copyright 2025 Jakub Stachurski
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use std::collections::VecDeque;
+
+use chrono::{DateTime, Utc};
+
+use crate::{error::ServerError, prelude::YnError};
+
+/// An error from the game script, as it is kept in the log
+#[derive(Clone, Debug)]
+pub struct LoggedError {
+    pub time: DateTime<Utc>,
+    /// The last message in the history when the error happened
+    pub seq: Option<u64>,
+    pub error: ServerError,
+}
+
+impl LoggedError {
+    pub fn new(seq: Option<u64>, error: ServerError) -> Self {
+        Self {
+            time: Utc::now(),
+            seq,
+            error,
+        }
+    }
+
+    /// The error as it is sent to the admins
+    pub fn to_yn_error(&self) -> YnError {
+        let mut e = YnError::from(self.error.clone());
+        if let Some(seq) = self.seq {
+            e.info = format!("{} (after message {})", e.info, seq);
+        }
+        e
+    }
+}
+
+/// The last errors of the game script, the oldest ones are dropped
+#[derive(Default)]
+pub struct ErrorLog {
+    entries: VecDeque<LoggedError>,
+    /// How many of the newest entries were not sent to the admins yet
+    unsent: usize,
+}
+
+impl ErrorLog {
+    const CAPACITY: usize = 256;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, error: LoggedError) {
+        if self.entries.len() == Self::CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(error);
+        self.unsent = (self.unsent + 1).min(Self::CAPACITY);
+    }
+
+    /// Returns the errors that were not sent yet, they count as sent afterwards
+    pub fn take_unsent(&mut self) -> Vec<LoggedError> {
+        let start = self.entries.len() - self.unsent;
+        self.unsent = 0;
+        self.entries.range(start..).cloned().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LoggedError> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
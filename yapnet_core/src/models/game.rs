@@ -0,0 +1,167 @@
+// Copyright 2025 Jakub Stachurski
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::{
+    chat::{Chat, Chats},
+    vote::{Vote, Votes},
+};
+use crate::prelude::*;
+use crate::protocol::{ActionId, ChatId, RoleId, UserId};
+
+/// Members of this group are game masters
+pub const ADMIN_GROUP: &str = "admin";
+
+pub type Players = BTreeMap<UserId, Player>;
+
+/// A player, as far as the history tells
+#[derive(Clone, Debug, PartialEq)]
+pub struct Player {
+    pub online: bool,
+    pub alive: bool,
+    /// Empty until it is revealed
+    pub role: Option<RoleId>,
+    /// Groups the player is a member of, used for chat permissions
+    pub groups: BTreeSet<String>,
+    /// The actions the player was last told they can do
+    pub actions: Vec<ActionInfo>,
+    /// The action the player picked in this phase, with its arguments
+    pub current_action: Option<(ActionId, Vec<String>)>,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            online: true,
+            alive: true,
+            role: None,
+            groups: BTreeSet::new(),
+            actions: vec![],
+            current_action: None,
+        }
+    }
+}
+
+impl Player {
+    pub fn is_admin(&self) -> bool {
+        self.groups.contains(ADMIN_GROUP)
+    }
+}
+
+/// Everything about the game that follows from the history.
+///
+/// The state is a fold of the history with [Game::reduce], the server and the clients use the
+/// same reducer, so they agree on the game as long as they saw the same messages.
+#[derive(Default)]
+pub struct Game {
+    pub players: Players,
+    pub chats: Chats,
+    pub phase: Phase,
+    pub votes: Votes,
+}
+
+impl Game {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds the game from the start by applying every message
+    pub fn fold<'a>(messages: impl IntoIterator<Item = &'a Message>) -> Self {
+        let mut game = Self::new();
+        for m in messages {
+            game.reduce(&m.data);
+        }
+        game
+    }
+
+    /// Applies a single message to the game, messages that do not change it are ignored.
+    /// Rewinds can not be applied on top, the game has to be folded again from what is left.
+    pub fn reduce(&mut self, msg: &MessageData) {
+        match msg {
+            MessageData::BodySetup(Setup { chats }) => {
+                self.chats = chats.iter().map(|c| (c.name.clone(), Self::make_chat(c))).collect();
+            }
+            MessageData::BodyChatCreated(ChatCreated { chat }) => {
+                self.chats.insert(chat.name.clone(), Self::make_chat(chat));
+            }
+            MessageData::BodyChatDeleted(ChatDeleted { name }) => {
+                self.chats.remove(name);
+                self.votes.remove(name);
+            }
+            MessageData::BodyPlayerJoined(PlayerJoined { username }) => {
+                self.players.entry(username.clone()).or_default().online = true;
+            }
+            MessageData::BodyPlayerLeft(PlayerLeft { username }) => {
+                self.players.entry(username.clone()).or_default().online = false;
+            }
+            MessageData::BodyRoleReveal(RoleReveal { user, role, .. }) => {
+                self.players.entry(user.clone()).or_default().role = Some(role.clone());
+            }
+            MessageData::BodyKillReveal(KillReveal { user, role }) => {
+                let player = self.players.entry(user.clone()).or_default();
+                player.alive = false;
+                if role.is_some() {
+                    player.role = role.clone();
+                }
+            }
+            MessageData::BodyGroupsChanged(GroupsChanged { user, groups, .. }) => {
+                self.players.entry(user.clone()).or_default().groups = groups.iter().cloned().collect();
+            }
+            MessageData::BodyPhaseChange(PhaseChange { phase }) => {
+                self.phase = phase.clone();
+                for player in self.players.values_mut() {
+                    player.current_action = None;
+                }
+            }
+            MessageData::BodyAvailableActions(AvailableActions { player, actions }) => {
+                self.players.entry(player.clone()).or_default().actions = actions.clone();
+            }
+            MessageData::BodyActionSelected(ActionSelected { player, action_id, args }) => {
+                self.players.entry(player.clone()).or_default().current_action =
+                    action_id.clone().map(|id| (id, args.clone()));
+            }
+            MessageData::BodyVoteStart(VoteStart { chat_id, subject, end_time }) => {
+                self.votes.insert(chat_id.clone(), Vote::new(subject.clone(), *end_time));
+            }
+            MessageData::BodyVoteSubmission(VoteSubmission { voter, target, chat_id }) => {
+                if let Some(vote) = self.votes.get_mut(chat_id) {
+                    vote.submit(voter.clone(), target.clone());
+                }
+            }
+            MessageData::BodyVoteResult(VoteResult { chat_id, .. }) => {
+                self.votes.remove(chat_id);
+            }
+            _ => {}
+        }
+    }
+
+    fn make_chat(setup: &ChatSetup) -> Chat {
+        let mut chat = Chat::new(setup.perm.clone());
+        chat.phases = setup.phases.clone();
+        chat
+    }
+
+    /// Returns the sorted names of the chats the player can read
+    pub fn readable_chats(&self, username: &UserId, player: &Player) -> Vec<ChatId> {
+        let mut chats: Vec<ChatId> = self
+            .chats
+            .iter()
+            .filter(|(_, chat)| chat.can_read(username, player))
+            .map(|(name, _)| name.clone())
+            .collect();
+        chats.sort();
+        chats
+    }
+}
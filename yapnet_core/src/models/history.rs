@@ -12,8 +12,6 @@
 //   See the License for the specific language governing permissions and
 //   limitations under the License.
 
-use std::usize;
-
 use crate::protocol::{body::MessageV2Enum, MessageV2};
 
 #[derive(Debug)]
@@ -38,6 +36,22 @@ impl History {
         }
     }
 
+    /// Rebuilds the history from messages that were numbered before
+    pub fn from_messages(messages: Vec<MessageV2>) -> Self {
+        let start = messages.first().map_or(0, |m| m.seq);
+        let seq = messages.last().map_or(0, |m| m.seq + 1);
+        History {
+            inner: messages,
+            start,
+            seq,
+        }
+    }
+
+    /// The sequence number the next message will get
+    pub fn next_seq(&self) -> u64 {
+        self.seq
+    }
+
     pub fn iter(&self) -> HistoryIter<'_> {
         HistoryIter {
             index: 0,
@@ -56,7 +70,7 @@ impl History {
     pub fn state_message(&mut self, m: MessageV2Enum) -> &MessageV2 {
         let s = self.seq;
         self.seq += 1;
-        let message = MessageV2 { seq: s, data: m };
+        let message = MessageV2 { seq: s, prev: None, data: m };
         self.inner.push(message);
         self.inner.last().expect("Just pushed")
     }
@@ -64,7 +78,7 @@ impl History {
     pub fn push_and_serialize(&mut self, m: MessageV2Enum) -> String {
         let s = self.seq;
         self.seq += 1;
-        let message = MessageV2 { seq: s, data: m };
+        let message = MessageV2 { seq: s, prev: None, data: m };
         let d = serde_json::to_string(&message).unwrap();
         // TODO: Handle error
         self.inner.push(message);
@@ -74,7 +88,7 @@ impl History {
     pub fn push(&mut self, m: MessageV2Enum) {
         let s = self.seq;
         self.seq += 1;
-        let message = MessageV2 { seq: s, data: m };
+        let message = MessageV2 { seq: s, prev: None, data: m };
         self.inner.push(message);
     }
 
@@ -91,6 +105,12 @@ impl History {
         self.inner.get((seq - self.start) as usize)
     }
 
+    /// Drops every message from this seq on
+    pub fn truncate(&mut self, seq: u64) {
+        self.inner.truncate(seq.saturating_sub(self.start) as usize);
+        self.seq = self.start + self.inner.len() as u64;
+    }
+
     pub fn remove_message(&mut self, seq: u64) -> bool {
         if seq < self.start || seq >= self.seq {
             return false; 
@@ -12,9 +12,13 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use crate::prelude::Message;
-
+pub mod action;
 pub mod chat;
+pub mod error_log;
+pub mod game;
 pub mod history;
+pub mod role;
+pub mod timer;
 pub mod user;
+pub mod vote;
 
@@ -0,0 +1,36 @@
+// Copyright 2025 Jakub Stachurski
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use std::collections::HashMap;
+
+use crate::protocol::RoleId;
+
+/// Roles declared by the game script
+pub type Roles = HashMap<RoleId, RoleDef>;
+
+pub struct RoleDef {
+    /// Players with these roles learn who has this role (e.g. mafia members know each other)
+    pub known_by: Vec<RoleId>,
+    /// Whether the role is shown to everyone when the player dies
+    pub reveal_on_death: bool,
+}
+
+impl RoleDef {
+    pub fn new(known_by: Vec<RoleId>, reveal_on_death: bool) -> Self {
+        Self {
+            known_by,
+            reveal_on_death,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2025 Jakub Stachurski
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub type TimerId = u64;
+
+/// Event scheduled by the game script
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Timer {
+    pub id: TimerId,
+    pub name: String,
+    pub payload: Value,
+    /// Wall clock time, so the timer keeps its place when restored from a snapshot
+    pub fire_at: DateTime<Utc>,
+}
+
+/// Pending timers, ordered by the time they fire at
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Timers {
+    next_id: TimerId,
+    pending: Vec<Timer>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The id the next scheduled timer should get
+    pub fn next_id(&self) -> TimerId {
+        self.next_id
+    }
+
+    pub fn insert(&mut self, timer: Timer) {
+        self.next_id = self.next_id.max(timer.id + 1);
+        let i = self.pending.partition_point(|t| t.fire_at <= timer.fire_at);
+        self.pending.insert(i, timer);
+    }
+
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        let len = self.pending.len();
+        self.pending.retain(|t| t.id != id);
+        len != self.pending.len()
+    }
+
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.pending.first().map(|t| t.fire_at)
+    }
+
+    /// Removes and returns the timers that should have fired by now
+    pub fn pop_due(&mut self, now: DateTime<Utc>) -> Vec<Timer> {
+        let i = self.pending.partition_point(|t| t.fire_at <= now);
+        self.pending.drain(..i).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Timer> {
+        self.pending.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
@@ -15,17 +15,26 @@
 
 use std::collections::HashMap;
 
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 pub type Users = HashMap<String, User>;
 
+/// What the server knows about a user that is not in the history,
+/// the rest of the player lives in [crate::models::game::Player]
+#[derive(Serialize, Deserialize)]
 pub struct User {
+    /// Nobody is connected after a restore, so this is not saved
+    #[serde(skip)]
     pub online: bool,
     pub uuid: Uuid,
 }
 
 impl User {
     pub fn new(uuid: Uuid) -> Self {
-        Self { uuid, online: true }
+        Self {
+            uuid,
+            online: true,
+        }
     }
 }
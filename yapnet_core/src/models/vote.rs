@@ -0,0 +1,112 @@
+// Copyright 2025 Jakub Stachurski
+//
+//   Licensed under the Apache License, Version 2.0 (the "License");
+//   you may not use this file except in compliance with the License.
+//   You may obtain a copy of the License at
+//
+//       http://www.apache.org/licenses/LICENSE-2.0
+//
+//   Unless required by applicable law or agreed to in writing, software
+//   distributed under the License is distributed on an "AS IS" BASIS,
+//   WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//   See the License for the specific language governing permissions and
+//   limitations under the License.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{ChatId, UserId};
+
+/// Votes that are currently running, at most one per chat
+pub type Votes = HashMap<ChatId, Vote>;
+
+#[derive(Serialize, Deserialize)]
+pub struct Vote {
+    pub subject: String,
+    pub end_time: DateTime<Utc>,
+    /// voter -> target
+    pub ballots: HashMap<UserId, UserId>,
+}
+
+impl Vote {
+    pub fn new(subject: String, end_time: DateTime<Utc>) -> Self {
+        Self {
+            subject,
+            end_time,
+            ballots: HashMap::new(),
+        }
+    }
+
+    pub fn is_over(&self, now: DateTime<Utc>) -> bool {
+        now >= self.end_time
+    }
+
+    /// Records the ballot, a voter can change their mind until the vote ends.
+    pub fn submit(&mut self, voter: UserId, target: UserId) {
+        self.ballots.insert(voter, target);
+    }
+
+    /// Returns the player with the most votes, or None if nobody voted or there is a tie.
+    pub fn tally(&self) -> Option<UserId> {
+        let mut counts: HashMap<&UserId, usize> = HashMap::new();
+        for target in self.ballots.values() {
+            *counts.entry(target).or_insert(0) += 1;
+        }
+
+        let mut winner = None;
+        let mut best = 0;
+        for (target, count) in counts {
+            if count > best {
+                best = count;
+                winner = Some(target.clone());
+            } else if count == best {
+                winner = None;
+            }
+        }
+        winner
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vote(ballots: &[(&str, &str)]) -> Vote {
+        let mut vote = Vote::new("Who is the mafia?".to_string(), Utc::now());
+        for (voter, target) in ballots {
+            vote.submit(voter.to_string(), target.to_string());
+        }
+        vote
+    }
+
+    #[test]
+    fn the_majority_wins() {
+        let vote = vote(&[("alice", "carol"), ("bob", "carol"), ("carol", "alice")]);
+        assert_eq!(vote.tally().as_deref(), Some("carol"));
+    }
+
+    #[test]
+    fn a_tie_has_no_winner() {
+        let vote = vote(&[("alice", "carol"), ("bob", "dave"), ("carol", "bob"), ("dave", "bob"), ("eve", "carol")]);
+        assert_eq!(vote.tally(), None);
+    }
+
+    #[test]
+    fn a_tie_below_the_winner_does_not_count() {
+        let vote = vote(&[("alice", "carol"), ("bob", "dave"), ("carol", "eve"), ("dave", "eve")]);
+        assert_eq!(vote.tally().as_deref(), Some("eve"));
+    }
+
+    #[test]
+    fn no_votes_have_no_winner() {
+        assert_eq!(vote(&[]).tally(), None);
+    }
+
+    #[test]
+    fn only_the_last_ballot_of_a_voter_counts() {
+        let vote = vote(&[("alice", "bob"), ("carol", "bob"), ("alice", "carol"), ("bob", "carol")]);
+        assert_eq!(vote.tally().as_deref(), Some("carol"));
+    }
+}
@@ -18,6 +18,6 @@ pub use crate::models::{
     user::{User, Users},
 };
 pub use crate::protocol::{
-    body::MessageV2Enum as MessageData, body::*, ChatSetup, MessageDataV2 as MessageBody,
-    MessageV2 as Message, Perm,
+    body::MessageV2Enum as MessageData, body::*, ActionArg, ActionInfo, ChatSetup,
+    MessageDataV2 as MessageBody, MessageV2 as Message, Perm, Phase,
 };
@@ -16,29 +16,34 @@ use serde_json::Value;
 use uuid::Uuid;
 use yapnet_macro::MessageDataV2;
 
-use super::{ChatId, ChatSetup, MessageV2, RoleId, UserId};
+use super::{ActionId, ActionInfo, ChatId, ChatSetup, MessageV2, Phase, RoleId, UserId};
 yapnet_macro::protocol_body! {
     /// Server: Game setup
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type="setp")]
+    #[msg_data(scope = "global", msg_type="setp")]
     pub struct Setup { pub chats: Vec<ChatSetup>, }
     // Player movement protocol
     /// Client: First time join
     #[derive(MessageDataV2)]
-    #[msg_data(msg_type = "helo", global = true)]
+    #[msg_data(msg_type = "helo", scope = "server")]
     pub struct Hello {
         #[msg_info(subject)]
         pub username: String
     }
-    /// Client: Reconnect
+    /// Client: Reconnect, the recap only has what came after the last message the client has
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "back")]
+    #[msg_data(scope = "server", msg_type = "back")]
     pub struct Back {
-        pub token: Uuid
+        pub token: Uuid,
+        #[serde(default)]
+        pub last_seq: Option<u64>,
+        /// Seq of the last rewind the client went through, the seqs after it are only trusted if it matches
+        #[serde(default)]
+        pub last_rewind: Option<u64>,
     }
     /// Server: Accept player
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "welc")]
+    #[msg_data(scope = "private", msg_type = "welc")]
     pub struct Welcome {
         #[msg_info(object)]
         pub username: String,
@@ -46,14 +51,14 @@ yapnet_macro::protocol_body! {
     }
     /// Server: Someone joined
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "plrj")]
+    #[msg_data(scope = "global", msg_type = "plrj")]
     pub struct PlayerJoined {
         #[msg_info(subject)]
         pub username: String
     }
     /// Server: Someone left
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "plrl")]
+    #[msg_data(scope = "global", msg_type = "plrl")]
     pub struct PlayerLeft {
         #[msg_info(subject)]
         pub username: String
@@ -62,14 +67,14 @@ yapnet_macro::protocol_body! {
     // Chat
     /// Client: Say this in this chat
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "chas")]
+    #[msg_data(scope = "server", msg_type = "chas")]
     pub struct ChatSend {
         pub chat_target: String,
         pub chat_content: String,
     }
     /// Server: This client said this in this chat
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "chat")]
+    #[msg_data(scope = "chat", msg_type = "chat")]
     pub struct ChatSent {
         #[msg_info(subject)]
         pub chat_sender: String,
@@ -78,116 +83,203 @@ yapnet_macro::protocol_body! {
         pub chat_content: String,
     }
 
+    /// Server: The game opened a new chat
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "global", msg_type = "chnw")]
+    pub struct ChatCreated {
+        pub chat: ChatSetup,
+    }
+    /// Server: The game closed this chat for good
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "global", msg_type = "chdl")]
+    pub struct ChatDeleted {
+        pub name: ChatId,
+    }
+    /// Server: The game tells this to a single player
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "private", msg_type = "sysm")]
+    pub struct SystemMessage {
+        #[msg_info(object)]
+        pub to: UserId,
+        pub content: String,
+    }
 
-    /// Server: This player has this role
+    /// Server: This player has this role, only sent to the player it is revealed to
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "revr")]
+    #[msg_data(scope = "private", msg_type = "revr")]
     pub struct RoleReveal {
-        user: UserId,
-        role: RoleId,
+        #[msg_info(object)]
+        pub to: UserId,
+        #[msg_info(subject)]
+        pub user: UserId,
+        pub role: RoleId,
     }
 
-    /// Server: This player has this role
+    /// Server: This player died, the role is empty if it stays secret
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "revk")]
+    #[msg_data(scope = "global", msg_type = "revk")]
     pub struct KillReveal {
-        user: UserId,
-        role: RoleId,
+        #[msg_info(subject)]
+        pub user: UserId,
+        pub role: Option<RoleId>,
+    }
+
+    /// Server: Your groups changed, so you can now read these chats
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "private", msg_type = "grpc")]
+    pub struct GroupsChanged {
+        #[msg_info(subject)]
+        pub user: UserId,
+        pub groups: Vec<String>,
+        pub chats: Vec<ChatId>,
+    }
+
+    /// Server: The game moved on to this phase
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "global", msg_type = "phas")]
+    pub struct PhaseChange {
+        pub phase: Phase,
     }
 
+    /// Server/Client: The game goes back to before this message, later messages are gone
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "global", msg_type = "rewd")]
+    pub struct Rewind {
+        pub seq: u64,
+    }
+
+    /// Client: Load the game script again, keeping the game as it is
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "server", msg_type = "rlod")]
+    pub struct Reload {}
+
     /// Player actions
     /// Server: This are the actions you can do.
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "aavl")]
+    #[msg_data(scope = "private", msg_type = "aavl")]
     pub struct AvailableActions {
-        actions: Vec<String>,
+        #[msg_info(subject)]
+        pub player: UserId,
+        pub actions: Vec<ActionInfo>,
     }
 
 
     /// Client: I do the following action with the following arguments
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "asub")]
+    #[msg_data(scope = "server", msg_type = "asub")]
     pub struct SubmitAction {
-        action_id: String,
-        args: Vec<String>,
+        pub action_id: ActionId,
+        pub args: Vec<String>,
+    }
+
+    /// Server: The player will do this action in this phase, no action id means they picked none
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "private", msg_type = "asel")]
+    pub struct ActionSelected {
+        #[msg_info(subject)]
+        pub player: UserId,
+        pub action_id: Option<ActionId>,
+        pub args: Vec<String>,
     }
 
     /// Server: A vote has started in this room with the following theme and end time.
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "vstt")]
+    #[msg_data(scope = "chat", msg_type = "vstt")]
     pub struct VoteStart {
         #[msg_info(chat)]
-        chat_id: ChatId,
-        subject: String,
-        end_time: DateTime<Utc>,
+        pub chat_id: ChatId,
+        pub subject: String,
+        pub end_time: DateTime<Utc>,
     }
 
     /// Client: I vote on this person in this chat
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "vsub")]
+    #[msg_data(scope = "server", msg_type = "vsub")]
     pub struct SubmitVote {
         #[msg_info(object)]
-        player_id: UserId,
+        pub player_id: UserId,
         #[msg_info(chat)]
-        chat_id: ChatId,
+        pub chat_id: ChatId,
     }
 
     /// Server: This user votes on this person in this chat
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "vrsb")]
+    #[msg_data(scope = "chat", msg_type = "vrsb")]
     pub struct VoteSubmission {
         #[msg_info(subject)]
-        voter: UserId, // TODO: Make option
+        pub voter: UserId, // TODO: Make option
         #[msg_info(object)]
-        target: UserId,
+        pub target: UserId,
         #[msg_info(chat)]
-        chat_id: ChatId,
+        pub chat_id: ChatId,
     }
 
     /// Server: The vote ended with the following result
+    /// The target is empty when nobody voted or the vote was tied
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "vres")]
+    #[msg_data(scope = "chat", msg_type = "vres")]
     pub struct VoteResult {
-        target: UserId,
-        chat_id: ChatId,
+        pub target: Option<UserId>,
+        #[msg_info(chat)]
+        pub chat_id: ChatId,
+    }
+
+    /// Client: I processed every message up to this seq
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "server", msg_type = "ackn")]
+    pub struct Ack {
+        pub seq: u64,
+    }
+
+    /// Client: I missed messages, send me what came after this seq again
+    #[derive(MessageDataV2)]
+    #[msg_data(scope = "server", msg_type = "rsyn")]
+    pub struct Resync {
+        pub last_seq: Option<u64>,
+        #[serde(default)]
+        pub last_rewind: Option<u64>,
     }
 
     /// Server: The action is submitted with the following result
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "ares")]
+    #[msg_data(scope = "server", msg_type = "ares")]
     pub struct ActionResult {
-        success: bool,
-        reason: String,
+        pub action_id: ActionId,
+        pub success: bool,
+        pub reason: String,
     }
 
 
     /// Server+Client, this went wrong
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "err")]
+    #[msg_data(scope = "server", msg_type = "err")]
     pub struct YnError {
         pub kind: String,
         pub info: String,
         pub details: String,
     }
     /// Sync
-    /// Server: This is how much happened before you joined
+    /// Server: This many messages happened before you joined, in chunks of this size
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "rech")]
+    #[msg_data(scope = "server", msg_type = "rech")]
     pub struct RecapHead {
         pub count: usize,
         pub chunk_sz: usize }
 
-    /// Server: This is what happened before you joined
+    /// Server: This is what happened before you joined, starting at the start-th message of the recap.
+    /// There is always at least one chunk, the last one is marked.
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "recx")]
+    #[msg_data(scope = "server", msg_type = "recx")]
     pub struct RecapTail {
+        pub index: usize,
         pub start: usize,
+        pub last: bool,
         pub msgs: Vec<Value> }
 
     // Misc
     /// Server/Client: Echo
     #[derive(MessageDataV2)]
-    #[msg_data(global=true, msg_type = "echo")]
+    #[msg_data(scope = "server", msg_type = "echo")]
     pub struct Echo(serde_json::Value);
 }
 
@@ -201,6 +293,7 @@ impl From<MessageV2Enum> for MessageV2 {
     fn from(value: MessageV2Enum) -> Self {
         MessageV2 {
             seq: 0,
+            prev: None,
             data: value,
         }
     }
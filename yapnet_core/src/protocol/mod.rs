@@ -14,21 +14,115 @@
 
 use serde::{Deserialize, Serialize};
 pub mod body;
+pub mod recap;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ChatSetup {
     pub name: String,
     pub perm: Perms,
+    /// Phases in which the chat can be written in, any phase if empty
+    #[serde(default)]
+    pub phases: Vec<Phase>,
+}
+
+/// An action a player can perform, as told to the client
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ActionInfo {
+    pub id: ActionId,
+    pub args: Vec<ActionArg>,
+}
+
+/// The kind of argument an action takes
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ActionArg {
+    /// Name of a player
+    #[serde(rename = "player")]
+    Player,
+    /// Name of a chat
+    #[serde(rename = "chat")]
+    Chat,
+    /// Any text
+    #[serde(rename = "text")]
+    Text,
+}
+
+/// Phases of the game, the scripts can declare their own on top of the built-in ones
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum Phase {
+    #[default]
+    Lobby,
+    Setup,
+    Day,
+    Night,
+    Ended,
+    Custom(PhaseId),
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Lobby => "lobby",
+            Self::Setup => "setup",
+            Self::Day => "day",
+            Self::Night => "night",
+            Self::Ended => "ended",
+            Self::Custom(name) => name,
+        }
+    }
+}
+
+impl From<String> for Phase {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "lobby" => Self::Lobby,
+            "setup" => Self::Setup,
+            "day" => Self::Day,
+            "night" => Self::Night,
+            "ended" => Self::Ended,
+            _ => Self::Custom(value),
+        }
+    }
+}
+
+impl From<Phase> for String {
+    fn from(value: Phase) -> Self {
+        value.as_str().to_string()
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 pub type UserId = String;
 pub type ChatId = String;
 pub type RoleId = String;
+pub type ActionId = String;
+pub type PhaseId = String;
+
+/// Who gets to see a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Everyone, including connections that did not log in yet
+    Global,
+    /// The players that can read the chat of the message
+    Chat,
+    /// The object of the message, or the subject if there is no object
+    Private,
+    /// Only goes between the server and a single connection, it is never broadcast or recapped
+    Server,
+}
 
 pub trait MessageDataV2 {
     /// Returns the msg_type field
     fn msg_type(&self) -> &'static str;
-    fn is_global(&self) -> bool;
+    fn scope(&self) -> Scope;
+    fn is_global(&self) -> bool {
+        self.scope() == Scope::Global
+    }
     fn subject(&self) -> Option<UserId>;
     fn object(&self) -> Option<UserId>;
     fn chat(&self) -> Option<ChatId>;
@@ -135,6 +229,10 @@ impl Perms {
 pub struct MessageV2 {
     #[serde(default)]
     pub seq: u64,
+    /// Seq of the message the server sent to this connection before this one, used to spot gaps.
+    /// Only set on messages from the history, when they are sent live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<u64>,
     #[serde(flatten)]
     pub data: body::MessageV2Enum,
 }
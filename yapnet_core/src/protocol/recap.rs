@@ -0,0 +1,84 @@
+// Copyright 2025 Jakub Stachurski
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use super::{
+    body::{RecapHead, RecapTail},
+    MessageV2,
+};
+
+#[derive(Debug)]
+pub enum RecapError {
+    /// The chunk is not the one that comes next
+    OutOfOrder { expected: usize, got: usize },
+    /// The recap ended with a different amount of messages than the head said
+    WrongCount { expected: usize, got: usize },
+    /// A chunk came after the last one
+    AlreadyDone,
+    BadMessage(String),
+}
+
+/// Puts the recap back together on the client, checking that no chunk went missing
+pub struct RecapReader {
+    count: usize,
+    next_index: usize,
+    received: usize,
+    done: bool,
+}
+
+impl RecapReader {
+    pub fn new(RecapHead { count, .. }: &RecapHead) -> Self {
+        Self {
+            count: *count,
+            next_index: 0,
+            received: 0,
+            done: false,
+        }
+    }
+
+    /// Reads the next chunk and returns its messages
+    pub fn read(&mut self, RecapTail { index, start, last, msgs }: &RecapTail) -> Result<Vec<MessageV2>, RecapError> {
+        if self.done {
+            return Err(RecapError::AlreadyDone);
+        }
+        if *index != self.next_index || *start != self.received {
+            return Err(RecapError::OutOfOrder {
+                expected: self.next_index,
+                got: *index,
+            });
+        }
+
+        let messages = msgs
+            .iter()
+            .map(|v| serde_json::from_value(v.clone()).map_err(|e| RecapError::BadMessage(e.to_string())))
+            .collect::<Result<Vec<MessageV2>, RecapError>>()?;
+        self.next_index += 1;
+        self.received += messages.len();
+
+        if *last {
+            self.done = true;
+            if self.received != self.count {
+                return Err(RecapError::WrongCount {
+                    expected: self.count,
+                    got: self.received,
+                });
+            }
+        }
+        Ok(messages)
+    }
+
+    /// True once the last chunk was read
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
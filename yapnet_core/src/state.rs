@@ -13,9 +13,11 @@
 //   limitations under the License.
 
 
-use std::{mem, ptr::from_ref};
+use std::{collections::{BTreeSet, HashMap}, io, mem, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+use chrono::{DateTime, Utc};
+use mlua::{prelude::{Lua, LuaError, LuaResult}, FromLuaMulti, IntoLuaMulti};
 use uuid::Uuid;
-use crate::{error::{ClientError, ServerError}, lua::LuaState, models::history::{self, History}, prelude::{MessageV2Enum as MessageData, *}, protocol::{ChatId, UserId}};
+use crate::{error::{ClientError, ServerError}, journal::{Journal, SnapshotRef}, lua::{read_game, yapi::load_lua, JsonArg, LuaState, StateFrame, Verdict}, models::{action::Actions, error_log::{ErrorLog, LoggedError}, history::History, role::Roles, timer::Timers, game::{Game, Player}}, prelude::{MessageV2Enum as MessageData, *}, protocol::{ActionId, ChatId, RoleId, Scope, UserId}};
 
 
 
@@ -38,13 +40,18 @@ impl ResponseFrame {
         } 
     }
 
-    pub fn broadcast(&mut self, msg: MessageData, chat: ChatId) {
+    /// Seq of the last message committed so far, counting the ones still in this frame
+    pub fn last_seq(&self) -> Option<u64> {
+        self.history.next_seq().checked_sub(1)
+    }
+
+    pub fn broadcast(&mut self, msg: MessageData) {
         let packet = self.history.state_message(msg);
-        self.responses.push(YapnetResponse::Broadcast(packet.seq, chat))
+        self.responses.push(YapnetResponse::Broadcast(packet.seq))
     }
-    pub fn broadcast_ex(&mut self, msg: MessageData, chat: ChatId) {
+    pub fn broadcast_ex(&mut self, msg: MessageData) {
         let packet = self.history.state_message(msg);
-        self.responses.push(YapnetResponse::BroadcastExclusive(packet.seq, chat))
+        self.responses.push(YapnetResponse::BroadcastExclusive(packet.seq))
     }
     pub fn error(&mut self, error: ClientError) {
         let i = self.ephemeral_messages.len();
@@ -58,13 +65,11 @@ impl ResponseFrame {
     } 
 
     pub fn ret_all(&mut self, recap: Vec<MessageData>) {
-        let mut i = self.ephemeral_messages.len();
         self.responses.reserve(recap.len());
         self.ephemeral_messages.reserve(recap.len());
         for r in recap.into_iter() {
-            self.responses.push(YapnetResponse::Return(i));
+            self.responses.push(YapnetResponse::Return(self.ephemeral_messages.len()));
             self.ephemeral_messages.push(r.into());
-            i += 1; 
         } 
     }
 
@@ -72,15 +77,15 @@ impl ResponseFrame {
         self.history.is_empty()
     } 
 
-    pub fn fetch_pair<'r>(&'r self, index: usize) -> Option<(&'r YapnetResponse, &'r Message)>{
+    pub fn fetch_pair(&self, index: usize) -> Option<(&YapnetResponse, &Message)>{
         if let Some(response) = self.responses.get(index){
             match &response {
-                YapnetResponse::Broadcast(seq,_) | YapnetResponse::BroadcastExclusive(seq,_) => {
+                YapnetResponse::Broadcast(seq) | YapnetResponse::BroadcastExclusive(seq) => {
                     let msg = self.history.get_message(*seq).expect("Responses should always have a matching message");
                     Some((response, msg))
 
                 },
-                YapnetResponse::None => { unreachable!() },
+                YapnetResponse::None | YapnetResponse::Admins(_) => { unreachable!() },
                 YapnetResponse::Return(id,) => {
                     let msg = self.ephemeral_messages.get(*id).expect("Responses should always have a matching message");
                     Some((response, msg))
@@ -93,123 +98,81 @@ impl ResponseFrame {
     }
 } 
 
-pub struct ResponseViewIter<'r> {
-    frame: &'r ResponseView<'r>,
-    response_index: usize,
-}
-
-impl<'r> ResponseViewIter<'r> {
-    pub fn new<'v>(rv: &'r ResponseView<'v>) -> Self 
-    where 'v:'r 
-    {
-        Self {
-            frame: rv, 
-            response_index: 0,
-        }
-
-    }
-}
-
-impl<'r> Iterator for ResponseViewIter<'r> {
-    type Item = (&'r YapnetResponse, &'r Message);
-
-    fn next(&mut self) -> Option<Self::Item> {
-       let i = self.frame.responses.len();
-       if self.response_index >= i {
-            None
-       } else {
-            let re = self.response_index; 
-            self.response_index += 1; 
-            let response = self.frame.responses.get(re).unwrap(); 
-            let message =  match response {
-                YapnetResponse::Broadcast(seq, _) |
-                YapnetResponse::BroadcastExclusive(seq, _) => { 
-                    if self.frame.history.is_some() { 
-                        self.frame.history.as_slice()[0].get_message(*seq)
-                    } else {
-                        // No history so skipping this value
-                        // TODO: Logging
-                        return self.next()
-                    }
-                },
-                YapnetResponse::Return(id) =>  self.frame.ephemeral_messages.get(*id),
-                YapnetResponse::None => unreachable!(),
-            }.expect("Responses should always have matching messages!");
-            Some((response, message))
-       }
-    }
-}
-
-
-
 #[derive(Debug, Clone)]
 pub enum YapnetResponse {
-    /// Send message to everyone
-    Broadcast(u64, ChatId),
-    /// Send message to everyone but the client who's message we are reacting too
-    BroadcastExclusive(u64, ChatId),
+    /// Send message to everyone in the scope of the message
+    Broadcast(u64),
+    /// Send message to everyone in the scope but the client who's message we are reacting too
+    BroadcastExclusive(u64),
     /// Only send the message to the one client who sent this message
     /// Used for errors
     /// Does not get pushed into the history
     Return(usize),
+    /// Send the message to every admin that is connected
+    /// Used for errors in the game script
+    /// Does not get pushed into the history
+    Admins(usize),
     /// Empty
     /// Does not get pushed into the history
     None,
 }
 
-#[derive(Debug, Clone)]
-pub struct ResponseView<'s> {
-    history: Option<&'s History>,
+/// The responses to a message, paired with copies of the messages they send.
+/// The view owns the messages, so it does not hold on to the state while they are sent.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseView {
     responses: Vec<YapnetResponse>,
-    ephemeral_messages: Vec<Message>,
+    messages: Vec<Message>,
 } 
 
-impl<'s> ResponseView<'s> {
-    pub fn new(history: &'s History, capacity: usize) -> Self {
+impl ResponseView {
+    pub fn new(capacity: usize) -> Self {
         Self {
-            history: Some(history), 
             responses: Vec::with_capacity(capacity),
-            ephemeral_messages: Vec::with_capacity(capacity),
+            messages: Vec::with_capacity(capacity),
         }
     }
 
-    fn new_ephemeral(capacity: usize) -> Self {
-        Self {
-            history: None, 
-            responses: Vec::with_capacity(capacity),
-            ephemeral_messages: Vec::with_capacity(capacity),
-        }
-    } 
+    /// A view without anything to send
+    pub fn empty() -> Self {
+        Self::default()
+    }
 
     pub fn from_message_return<T: IntoMessage>(msg: T) -> Self {
-        let mut s = Self::new_ephemeral(1); 
-        s.ephemeral_messages.push(msg.into_message());
+        let mut s = Self::new(1); 
+        s.messages.push(msg.into_message());
         s.responses.push(YapnetResponse::Return(0));
         s
     }
 
+    /// True if nothing in the view goes into the history
     pub fn is_ephemeral(&self) -> bool {
-        self.history.is_some()
+        self.responses.iter().all(|r| matches!(r, YapnetResponse::Return(_) | YapnetResponse::None))
     } 
 
-    fn push_frame<'a>(&mut self, frame: ResponseFrame, mut_history: &'a mut History) where 
-        's: 'a 
-    {
-        mut_history.merge(frame.history);
-        let i = self.ephemeral_messages.len(); 
-        self.responses.extend(frame.responses.into_iter().map(|r| match r {
-            YapnetResponse::Return(id) => YapnetResponse::Return(id + i),
-            x => x,
-        }));
-        self.ephemeral_messages.extend(frame.ephemeral_messages);
+    /// Merges the frame into the history, returns the messages it added
+    fn push_frame(&mut self, frame: ResponseFrame, history: &mut History) -> Vec<Message> {
+        for i in 0..frame.responses.len() {
+            let (response, msg) = frame.fetch_pair(i).expect("Responses should always have a matching message");
+            self.responses.push(match response {
+                YapnetResponse::Return(_) => YapnetResponse::Return(self.messages.len()),
+                x => x.clone(),
+            });
+            self.messages.push(msg.clone());
+        }
+        let merged = frame.history.iter().cloned().collect();
+        history.merge(frame.history);
+        merged
     }
 
-    fn set_history(&mut self, history: &'s History) {
-        self.history = Some(history);
+    /// Adds a message for the admins
+    fn push_admins(&mut self, msg: Message) {
+        self.responses.push(YapnetResponse::Admins(self.messages.len()));
+        self.messages.push(msg);
     }
 
-    pub fn iter<'r>(&'s self) -> ResponseViewIter<'r> where 's:'r {
-        ResponseViewIter::new(self)
+    pub fn iter(&self) -> impl Iterator<Item = (&YapnetResponse, &Message)> {
+        self.responses.iter().zip(self.messages.iter())
     }
 }
 
@@ -217,62 +180,264 @@ pub struct YapnetState {
     pub lua_state: Option<LuaState>, 
     outbound: Vec<ResponseFrame>,
     history: History,
-    pub chats: Chats, 
+    /// Everything that follows from the history, only changed through commit
+    pub game: Game,
     pub users: Users, 
+    pub actions: Actions,
+    pub roles: Roles,
+    /// Custom phases declared by the game script
+    pub phases: Vec<Phase>,
+    pub timers: Timers,
+    journal: Option<Journal>,
+    /// Where the game script was loaded from, the game can only be reloaded when this is set
+    pub script: Option<PathBuf>,
+    /// Errors from the game script, the new ones are sent to the admins
+    pub errors: ErrorLog,
+    /// True while the phase hooks run, the phase changes they ask for wait in queued_phase
+    changing_phase: bool,
+    queued_phase: Option<Phase>,
 } 
 
+impl Default for YapnetState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl YapnetState {
     const RECAP_CHUNK_SZ: usize = 64;
+    /// How many times the phase hooks can change the phase again before it is seen as a loop
+    const MAX_CHAINED_PHASES: usize = 16;
     pub fn new() -> Self {
         Self {
             lua_state: None,
             outbound: Vec::new(),
             history: History::new(),
-            chats: Chats::new(),
+            game: Game::new(),
             users: Users::new(),
+            actions: Actions::new(),
+            roles: Roles::new(),
+            phases: Vec::new(),
+            timers: Timers::new(),
+            journal: None,
+            script: None,
+            errors: ErrorLog::new(),
+            changing_phase: false,
+            queued_phase: None,
         }
     }
 
-    pub fn consume_frames<'s,'a>(&'s mut self) -> ResponseView<'a> 
-    where  
-       'a:'s 
-    {
-        let cap = self.outbound.capacity();
-        let out = mem::replace(&mut self.outbound, Vec::with_capacity(cap)); 
-        let mut view = ResponseView::new_ephemeral(self.outbound.len()*2);
-        for frame in out.into_iter() {
-            view.push_frame(frame, &mut self.history);
+    /// Starts saving the game into a new journal in the directory,
+    /// a game that is saved there already is only replaced with overwrite
+    pub fn start_journal(&mut self, dir: &Path, overwrite: bool) -> io::Result<()> {
+        let mut journal = Journal::create(dir, overwrite)?;
+        journal.append(self.history.iter())?;
+        self.journal = Some(journal);
+        self.persist(&[])
+    }
+
+    /// Continues the game saved in the directory, the players can come back with their old tokens
+    pub fn resume(&mut self, dir: &Path) -> io::Result<()> {
+        let (journal, messages, snapshot) = Journal::open(dir)?;
+        self.outbound.clear();
+        self.history = History::from_messages(messages);
+        self.game = Game::fold(self.history.iter());
+        self.users = snapshot.users;
+        self.timers = snapshot.timers;
+        self.journal = Some(journal);
+
+        // Nobody is connected yet, which the history should say as well
+        let mut frame = ResponseFrame::new(&self.history, self.users.len());
+        self.sync_presence(&mut frame);
+        self.outbound.push(frame);
+        self.consume_frames();
+        Ok(())
+    }
+
+    /// Applies the message to the game and sends it out, every change to the game goes through here
+    fn commit(&mut self, frame: &mut ResponseFrame, msg: MessageData) {
+        self.game.reduce(&msg);
+        frame.broadcast(msg);
+    }
+
+    /// Tells everyone about players whose connection differs from what the history says
+    fn sync_presence(&mut self, frame: &mut ResponseFrame) {
+        for username in self.sorted_usernames() {
+            let online = self.users.get(&username).is_some_and(|u| u.online);
+            if self.game.players.get(&username).map(|p| p.online) == Some(online) {
+                continue;
+            }
+            let msg = match online {
+                true => PlayerJoined { username }.into(),
+                false => PlayerLeft { username }.into(),
+            };
+            self.commit(frame, msg);
         }
-    
-        view.set_history(unsafe { mem::transmute::<&'s History ,&'a History>(&self.history) } );
-        view.clone() 
     }
 
-    
+    /// Takes the game back to before the message with this seq and folds the game again from the
+    /// messages that are left. Players that joined later stay, but lose their game state.
+    /// Timers are kept, since they are not in the history.
+    pub fn rewind(&mut self, seq: u64) -> Result<ResponseView, ServerError> {
+        if !self.can_rewind(seq) {
+            return Err(ServerError::Custom(
+                "Cannot rewind".to_string(),
+                format!("There is no message {} to go back to", seq),
+            ));
+        }
+        self.rewind_history(seq);
+        Ok(self.consume_frames())
+    }
+
+    /// The setup message can not be rewound
+    fn can_rewind(&self, seq: u64) -> bool {
+        seq > 0 && seq < self.history.next_seq()
+    }
 
-    pub fn handle_message_serveir<'s>(&'s mut self, username: &String, m: Message) -> ResponseView<'s>{
+    fn rewind_history(&mut self, seq: u64) {
+        self.history.truncate(seq);
+        self.game = Game::fold(self.history.iter());
+        if let Some(journal) = self.journal.as_mut() {
+            if let Err(e) = journal.rewrite(self.history.iter()) {
+                eprintln!("Cannot write the journal: {}", e);
+            }
+        }
+
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        self.commit(&mut frame, Rewind { seq }.into());
+        self.sync_presence(&mut frame);
+        self.refresh_actions(&mut frame);
+        self.outbound.push(frame);
+    }
+
+    fn handle_rewind(&mut self, username: &String, seq: u64) {
+        let error = if !self.game.players.get(username).is_some_and(Player::is_admin) {
+            ClientError::NoPermission("rewind".to_string(), "Only admins can rewind the game".to_string())
+        } else if !self.can_rewind(seq) {
+            ClientError::Custom("Cannot rewind".to_string(), format!("There is no message {} to go back to", seq))
+        } else {
+            self.rewind_history(seq);
+            return;
+        };
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        frame.error(error);
+        self.outbound.push(frame);
+    }
+
+    /// Swaps in a new game script. Chats the script declares that the game does not have yet are
+    /// created, the existing chats, the players and the history are left alone.
+    /// If the script cannot be read, the old one keeps running.
+    pub fn reload(&mut self, lua: Lua) -> Result<ResponseView, ServerError> {
+        self.swap_script(lua)
+            .map_err(|e| ServerError::Custom("Cannot reload the game script".to_string(), e.to_string()))?;
+        Ok(self.consume_frames())
+    }
+
+    fn swap_script(&mut self, lua: Lua) -> LuaResult<()> {
+        let script = read_game(&lua).map_err(LuaError::external)?;
+        self.phases = script.phases;
+        self.roles = script.roles;
+        self.actions = script.actions;
+        self.lua_state = Some(LuaState { lua });
+
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        for chat in script.chats {
+            if !self.game.chats.contains_key(&chat.name) {
+                self.commit(&mut frame, ChatCreated { chat }.into());
+            }
+        }
+        self.refresh_actions(&mut frame);
+        self.outbound.push(frame);
+        Ok(())
+    }
+
+    fn handle_reload(&mut self, username: &String) {
+        let error = if !self.game.players.get(username).is_some_and(Player::is_admin) {
+            ClientError::NoPermission("reload".to_string(), "Only admins can reload the game script".to_string())
+        } else {
+            let res = match self.script.clone() {
+                Some(path) => {
+                    let limits = self.lua_state.as_ref().map(LuaState::limits).unwrap_or_default();
+                    load_lua(&path, limits).and_then(|lua| self.swap_script(lua))
+                }
+                None => Err(LuaError::runtime("The game was not loaded from a file")),
+            };
+            match res {
+                Ok(()) => return,
+                Err(e) => ClientError::Custom("Cannot reload the game script".to_string(), e.to_string()),
+            }
+        };
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        frame.error(error);
+        self.outbound.push(frame);
+    }
+
+    /// Writes the new messages and the state to the journal, if there is one
+    fn persist(&mut self, merged: &[Message]) -> io::Result<()> {
+        let Some(journal) = self.journal.as_mut() else {
+            return Ok(());
+        };
+        journal.append(merged.iter())?;
+        journal.snapshot(&SnapshotRef {
+            users: &self.users,
+            timers: &self.timers,
+        })
+    }
+
+    pub fn consume_frames(&mut self) -> ResponseView {
+        let out = mem::take(&mut self.outbound);
+        let mut view = ResponseView::new(out.len() * 2);
+        let changed = !out.is_empty();
+        let mut merged = Vec::new();
+        for frame in out.into_iter() {
+            merged.extend(view.push_frame(frame, &mut self.history));
+        }
+        for error in self.errors.take_unsent() {
+            view.push_admins(error.to_yn_error().into_message());
+        }
+        if changed {
+            if let Err(e) = self.persist(&merged) {
+                eprintln!("Cannot write the journal: {}", e);
+            }
+        }
+        view
+    }
+
+    pub fn handle_message_serveir(&mut self, username: &String, m: Message) -> ResponseView{
         match m.data {
-            MessageData::BodyBack { .. } | MessageData::BodyHello { .. } => {
-                unreachable!("Back and Hello should be already handled")
+            MessageData::BodyBack { .. } | MessageData::BodyHello { .. } | MessageData::BodyAck { .. } => {
+                unreachable!("Back, Hello and Ack should be already handled")
             }
             MessageData::BodyChatSend { .. } => self.handle_chat(username, m),
+            MessageData::BodySubmitVote { .. } => self.handle_vote(username, m),
+            MessageData::BodySubmitAction { .. } => self.handle_action(username, m),
+            MessageData::BodyRewind(Rewind { seq }) => self.handle_rewind(username, seq),
+            MessageData::BodyReload(Reload {}) => self.handle_reload(username),
+            MessageData::BodyResync(Resync { last_seq, last_rewind }) => return self.resync(username, last_seq, last_rewind),
             MessageData::BodyWelcome { .. }
             | MessageData::BodyChatSent { .. }
             | MessageData::BodyPlayerLeft { .. }
             | MessageData::BodyPlayerJoined { .. }
             | MessageData::BodyRecapHead { .. }
             | MessageData::BodyRecapTail { .. }
+            | MessageData::BodyVoteStart { .. }
+            | MessageData::BodyVoteSubmission { .. }
+            | MessageData::BodyVoteResult { .. }
+            | MessageData::BodyAvailableActions { .. }
+            | MessageData::BodyActionResult { .. }
+            | MessageData::BodyRoleReveal { .. }
+            | MessageData::BodyActionSelected { .. }
+            | MessageData::BodyKillReveal { .. }
+            | MessageData::BodyPhaseChange { .. }
+            | MessageData::BodyGroupsChanged { .. }
+            | MessageData::BodyChatCreated { .. }
+            | MessageData::BodyChatDeleted { .. }
+            | MessageData::BodySystemMessage { .. }
             | MessageData::BodySetup { .. } => {
                 eprintln!("Server side packet sent by client!");
             }
             MessageData::BodyEcho(_) => todo!("echo"),
             MessageData::BodyYnError { .. } => todo!("error"),
-            x => {
-                eprintln!(
-                    "Unknown protocol message found {} ",
-                    x.to_inner().msg_type()
-                );
-            }
         }
         self.consume_frames()
     }
@@ -283,17 +448,36 @@ impl YapnetState {
             chat_content,
         }) = m.data {
             let mut frame = ResponseFrame::new(&self.history, 2); 
-            let player = self.users.get(sender).expect("");
-            if let Some(chat) = self.chats.get(&chat_target) {
-                if chat.can_write(player) {
-                    frame.broadcast(
-                                ChatSent {
-                                    chat_sender: sender.clone(),
-                                    chat_target: chat_target.clone(),
-                                    chat_content,
-                                }
-                                .into(),
-                        sender.clone());
+            let player = self.game.players.get(sender).expect("Authenticated users should exist");
+            if let Some(chat) = self.game.chats.get(&chat_target) {
+                if !chat.is_open(&self.game.phase) {
+                    frame.error(ClientError::NoPermission(chat_target,format!("The chat is closed during {}", self.game.phase)));
+                } else if chat.can_write(sender, player) {
+                    // A failing script should not take the chat down with it
+                    let (verdict, script) = self.run_callback::<_, Verdict>(
+                        frame.last_seq(),
+                        "on_chat",
+                        (chat_target.clone(), sender.clone(), chat_content.clone()),
+                    );
+                    let chat_content = match verdict.unwrap_or_default() {
+                        Verdict::Accept => Some(chat_content),
+                        Verdict::Rewrite(content) => Some(content),
+                        Verdict::Reject(reason) => {
+                            frame.error(Self::rejected(reason));
+                            None
+                        }
+                    };
+                    if let Some(chat_content) = chat_content {
+                        self.commit(
+                                    &mut frame,
+                                    ChatSent {
+                                        chat_sender: sender.clone(),
+                                        chat_target,
+                                        chat_content,
+                                    }
+                                    .into());
+                    }
+                    self.apply_script(&mut frame, script);
                 } else { 
                     frame.error(ClientError::NoPermission(chat_target,"".to_string()));
                 }
@@ -305,7 +489,554 @@ impl YapnetState {
             unreachable!("handle_chat is always used with ChatSend packets")
         }
     }
-    pub fn reauth_user(&mut self, token: Uuid) -> Result<(String,ResponseView<'_>), ServerError> {
+
+    fn handle_vote(&mut self, voter: &String, m: Message) {
+        if let MessageData::BodySubmitVote(SubmitVote {
+            player_id,
+            chat_id,
+        }) = m.data {
+            let mut frame = ResponseFrame::new(&self.history, 1);
+            match self.check_vote(voter, &player_id, &chat_id) {
+                Ok(()) => {
+                    let (verdict, script) = self.run_callback::<_, Verdict>(
+                        frame.last_seq(),
+                        "on_vote",
+                        (chat_id.clone(), voter.clone(), player_id.clone()),
+                    );
+                    // A rewrite changes who the vote goes to, so that player is checked again
+                    let target = match verdict.unwrap_or_default() {
+                        Verdict::Accept => Ok(player_id),
+                        Verdict::Rewrite(target) => self.check_vote(voter, &target, &chat_id).map(|()| target),
+                        Verdict::Reject(reason) => Err(Self::rejected(reason)),
+                    };
+                    match target {
+                        Ok(target) => self.commit(
+                            &mut frame,
+                            VoteSubmission {
+                                voter: voter.clone(),
+                                target,
+                                chat_id: chat_id.clone(),
+                            }
+                            .into()),
+                        Err(e) => frame.error(e),
+                    }
+                    self.apply_script(&mut frame, script);
+                }
+                Err(e) => frame.error(e),
+            }
+            self.outbound.push(frame);
+        } else {
+            unreachable!("handle_vote is always used with SubmitVote packets")
+        }
+    }
+
+    /// Checks that there is a vote running in the chat and that the voter is allowed to cast the ballot.
+    fn check_vote(&self, voter: &String, target: &UserId, chat_id: &ChatId) -> Result<(), ClientError> {
+        let chat = self.game.chats.get(chat_id)
+            .ok_or_else(|| ClientError::InvalidChat(chat_id.clone(), "Not found".to_string()))?;
+        let player = self.game.players.get(voter).expect("Authenticated users should exist");
+        if !chat.can_write(voter, player) || !chat.is_open(&self.game.phase) {
+            return Err(ClientError::NoPermission(chat_id.clone(), "Cannot vote in this chat".to_string()));
+        }
+        if !self.game.players.contains_key(target) {
+            return Err(ClientError::InvalidObject(target.clone(), "Not a player".to_string()));
+        }
+        match self.game.votes.get(chat_id) {
+            Some(vote) if !vote.is_over(Utc::now()) => Ok(()),
+            _ => Err(ClientError::InvalidChat(chat_id.clone(), "No vote in progress".to_string())),
+        }
+    }
+
+    /// Opens a vote in a chat, there can only be one vote running per chat.
+    pub fn start_vote(&mut self, chat_id: ChatId, subject: String, end_time: DateTime<Utc>) -> Result<ResponseView, ServerError> {
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        self.open_vote(&mut frame, chat_id, subject, end_time)?;
+        self.outbound.push(frame);
+        Ok(self.consume_frames())
+    }
+
+    fn open_vote(&mut self, frame: &mut ResponseFrame, chat_id: ChatId, subject: String, end_time: DateTime<Utc>) -> Result<(), ServerError> {
+        if !self.game.chats.contains_key(&chat_id) {
+            return Err(ServerError::Custom("Cannot start vote".to_string(), format!("The chat {} does not exist", chat_id)));
+        }
+        if self.game.votes.contains_key(&chat_id) {
+            return Err(ServerError::Custom("Cannot start vote".to_string(), format!("A vote is already running in {}", chat_id)));
+        }
+
+        self.commit(
+            frame,
+            VoteStart {
+                chat_id: chat_id.clone(),
+                subject,
+                end_time,
+            }
+            .into());
+        Ok(())
+    }
+
+    /// Closes all votes that ran out of time and fires the timers that are due.
+    pub fn tick(&mut self) -> ResponseView {
+        let now = Utc::now();
+        let mut ended: Vec<(DateTime<Utc>, ChatId)> = self.game.votes
+            .iter()
+            .filter(|(_, vote)| vote.is_over(now))
+            .map(|(chat_id, vote)| (vote.end_time, chat_id.clone()))
+            .collect();
+        ended.sort();
+        let due = self.timers.pop_due(now);
+        if ended.is_empty() && due.is_empty() {
+            // Woken up before anything was due, nothing changed so nothing is written
+            return self.consume_frames();
+        }
+
+        let mut frame = ResponseFrame::new(&self.history, ended.len());
+        for (_, chat_id) in ended {
+            self.close_vote(&mut frame, chat_id);
+        }
+        for timer in due {
+            let (_, script) = self.run_callback::<_, ()>(
+                frame.last_seq(),
+                "on_timer",
+                (timer.name, JsonArg(timer.payload), timer.id),
+            );
+            self.apply_script(&mut frame, script);
+        }
+        self.outbound.push(frame);
+        self.consume_frames()
+    }
+
+    /// When the next vote ends or timer fires, tick should be called then.
+    pub fn next_deadline(&self) -> Option<DateTime<Utc>> {
+        self.game.votes
+            .values()
+            .map(|vote| vote.end_time)
+            .chain(self.timers.next_deadline())
+            .min()
+    }
+
+    fn close_vote(&mut self, frame: &mut ResponseFrame, chat_id: ChatId) {
+        let target = self.game.votes.get(&chat_id).expect("Only running votes are closed").tally();
+        self.commit(
+            frame,
+            VoteResult {
+                target: target.clone(),
+                chat_id: chat_id.clone(),
+            }
+            .into());
+
+        let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_vote_result", (chat_id, target));
+        self.apply_script(frame, script);
+    }
+
+    fn handle_action(&mut self, username: &String, m: Message) {
+        if let MessageData::BodySubmitAction(SubmitAction { action_id, args }) = m.data {
+            let mut frame = ResponseFrame::new(&self.history, 2);
+            match self.check_action(username, &action_id, &args) {
+                Ok(()) => {
+                    let (verdict, script) = self.run_callback::<_, (Option<bool>, Option<String>)>(
+                        frame.last_seq(),
+                        "on_action",
+                        (username.clone(), action_id.clone(), args.clone()),
+                    );
+                    let (success, reason) = match verdict {
+                        Some((ok, reason)) => (ok.unwrap_or(true), reason.unwrap_or_default()),
+                        None => (false, "The game script failed to handle the action".to_string()),
+                    };
+                    frame.ret(ActionResult { action_id: action_id.clone(), success, reason }.into());
+                    if success {
+                        let selected = ActionSelected { player: username.clone(), action_id: Some(action_id), args };
+                        self.commit(&mut frame, selected.into());
+                    }
+                    self.apply_script(&mut frame, script);
+                }
+                Err(e) => frame.error(e),
+            }
+            self.outbound.push(frame);
+        } else {
+            unreachable!("handle_action is always used with SubmitAction packets")
+        }
+    }
+
+    /// Checks that the action is available to the player and that the arguments fit its schema.
+    fn check_action(&self, username: &String, action_id: &ActionId, args: &[String]) -> Result<(), ClientError> {
+        let player = self.game.players.get(username).expect("Authenticated users should exist");
+        if !player.alive {
+            return Err(ClientError::NoPermission(action_id.clone(), "Dead players cannot act".to_string()));
+        }
+        let action = self.actions
+            .get(action_id)
+            .filter(|a| a.allows(player.role.as_ref(), &self.game.phase))
+            .ok_or_else(|| ClientError::InvalidAction(action_id.clone(), "Not available".to_string()))?;
+
+        if action.args.len() != args.len() {
+            return Err(ClientError::InvalidAction(
+                action_id.clone(),
+                format!("Expected {} arguments, got {}", action.args.len(), args.len()),
+            ));
+        }
+        for (kind, arg) in action.args.iter().zip(args) {
+            match kind {
+                ActionArg::Player if !self.game.players.contains_key(arg) => {
+                    return Err(ClientError::InvalidObject(arg.clone(), "Not a player".to_string()))
+                }
+                ActionArg::Chat if !self.game.chats.contains_key(arg) => {
+                    return Err(ClientError::InvalidChat(arg.clone(), "Not found".to_string()))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the actions the player can do right now
+    pub fn available_actions(&self, username: &String) -> Vec<ActionInfo> {
+        match self.game.players.get(username).filter(|p| p.alive) {
+            Some(player) => self.actions
+                .iter()
+                .filter(|(_, a)| a.allows(player.role.as_ref(), &self.game.phase))
+                .map(|(id, a)| a.info(id))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Tells every player whose available actions changed what they can do now.
+    fn refresh_actions(&mut self, frame: &mut ResponseFrame) {
+        for username in self.sorted_usernames() {
+            let actions = self.available_actions(&username);
+            if self.game.players.get(&username).is_some_and(|p| p.actions != actions) {
+                self.commit(frame, AvailableActions { player: username.clone(), actions }.into());
+            }
+        }
+    }
+
+    /// Moves the game to another phase.
+    pub fn set_phase(&mut self, phase: Phase) -> Result<ResponseView, ServerError> {
+        let mut frame = ResponseFrame::new(&self.history, 2);
+        self.change_phase(&mut frame, phase)?;
+        self.refresh_actions(&mut frame);
+        self.outbound.push(frame);
+        Ok(self.consume_frames())
+    }
+
+    /// Runs the exit and enter callbacks around the phase change,
+    /// entering the setup phase hands out the roles.
+    /// Phase changes asked for by the hooks are done after the current one, the last one asked for wins.
+    fn change_phase(&mut self, frame: &mut ResponseFrame, phase: Phase) -> Result<(), ServerError> {
+        if let Phase::Custom(_) = phase {
+            if !self.phases.contains(&phase) {
+                return Err(ServerError::Custom("Cannot change phase".to_string(), format!("The phase {} is not declared", phase)));
+            }
+        }
+        if self.changing_phase {
+            self.queued_phase = Some(phase);
+            return Ok(());
+        }
+
+        self.changing_phase = true;
+        let res = self.enter_phase(frame, phase);
+        let mut chained = 0;
+        while let Some(next) = self.queued_phase.take() {
+            if chained == Self::MAX_CHAINED_PHASES {
+                self.script_error(frame.last_seq(), ServerError::Custom(
+                    "Cannot change phase".to_string(),
+                    format!("The phase hooks changed the phase {} times in a row, stopped before {}", chained, next),
+                ));
+                break;
+            }
+            chained += 1;
+            if let Err(e) = self.enter_phase(frame, next) {
+                self.script_error(frame.last_seq(), e);
+            }
+        }
+        self.changing_phase = false;
+        res
+    }
+
+    fn enter_phase(&mut self, frame: &mut ResponseFrame, phase: Phase) -> Result<(), ServerError> {
+        if phase == self.game.phase {
+            return Ok(());
+        }
+
+        let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_phase_exit", self.game.phase.to_string());
+        self.apply_script(frame, script);
+
+        self.commit(frame, PhaseChange { phase: phase.clone() }.into());
+
+        if phase == Phase::Setup {
+            self.run_role_assignment(frame);
+        }
+        let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_phase_enter", phase.to_string());
+        self.apply_script(frame, script);
+        Ok(())
+    }
+
+    /// Asks the game script to hand out the roles and reveals them to whoever should know them.
+    pub fn assign_roles(&mut self) -> ResponseView {
+        let mut frame = ResponseFrame::new(&self.history, self.users.len());
+        self.run_role_assignment(&mut frame);
+        self.outbound.push(frame);
+        self.consume_frames()
+    }
+
+    fn run_role_assignment(&mut self, frame: &mut ResponseFrame) {
+        let players = self.sorted_usernames();
+        let (assignment, script) = self.run_callback::<_, HashMap<UserId, RoleId>>(frame.last_seq(), "assign_roles", players.clone());
+
+        let mut assignment = assignment.unwrap_or_default();
+        let strangers: Vec<String> = assignment.keys().filter(|u| !self.game.players.contains_key(*u)).cloned().collect();
+        for username in strangers {
+            self.script_error(frame.last_seq(), ServerError::Custom(
+                "Cannot set role".to_string(),
+                format!("assign_roles gave a role to {}, who is not a player", username),
+            ));
+        }
+
+        // Everyone learns their own role first, so the others can be revealed by it
+        for username in players.iter() {
+            match assignment.remove(username) {
+                Some(role) if self.roles.contains_key(&role) => {
+                    let reveal = RoleReveal { to: username.clone(), user: username.clone(), role };
+                    self.commit(frame, reveal.into());
+                }
+                Some(role) => self.script_error(frame.last_seq(), ServerError::Custom(
+                    "Cannot set role".to_string(),
+                    format!("assign_roles gave {} the unknown role {}", username, role),
+                )),
+                None => {}
+            }
+        }
+        for viewer in players.iter() {
+            for player in players.iter().filter(|p| *p != viewer) {
+                self.reveal_role(frame, viewer, player);
+            }
+        }
+        self.apply_script(frame, script);
+    }
+
+    fn set_role(&mut self, frame: &mut ResponseFrame, username: UserId, role: RoleId) -> Result<(), ServerError> {
+        if !self.roles.contains_key(&role) {
+            return Err(ServerError::Custom("Cannot set role".to_string(), format!("The role {} does not exist", role)));
+        }
+        if !self.game.players.contains_key(&username) {
+            return Err(ServerError::Custom("Cannot set role".to_string(), format!("{} is not a player", username)));
+        }
+
+        let reveal = RoleReveal { to: username.clone(), user: username.clone(), role };
+        self.commit(frame, reveal.into());
+        for other in self.sorted_usernames().into_iter().filter(|o| *o != username) {
+            self.reveal_role(frame, &other, &username);
+            self.reveal_role(frame, &username, &other);
+        }
+        Ok(())
+    }
+
+    /// Tells the viewer the role of the player, if they are allowed to know it.
+    fn reveal_role(&mut self, frame: &mut ResponseFrame, viewer: &UserId, player: &UserId) {
+        let (Some(v), Some(p)) = (self.game.players.get(viewer), self.game.players.get(player)) else {
+            return;
+        };
+        let Some(role) = p.role.clone() else {
+            return;
+        };
+        let known = viewer == player
+            || v.role.as_ref().is_some_and(|vr| self.roles.get(&role).is_some_and(|def| def.known_by.contains(vr)));
+        if known {
+            let reveal = RoleReveal { to: viewer.clone(), user: player.clone(), role };
+            self.commit(frame, reveal.into());
+        }
+    }
+
+    /// Replaces the groups of a player and tells them which chats they can read now.
+    fn set_groups(&mut self, frame: &mut ResponseFrame, username: UserId, groups: BTreeSet<String>) -> Result<(), ServerError> {
+        let mut player = self.game.players
+            .get(&username)
+            .cloned()
+            .ok_or_else(|| ServerError::Custom("Cannot change groups".to_string(), format!("{} is not a player", username)))?;
+        if player.groups == groups {
+            return Ok(());
+        }
+        player.groups = groups;
+
+        let changed = GroupsChanged {
+            user: username.clone(),
+            groups: player.groups.iter().cloned().collect(),
+            chats: self.game.readable_chats(&username, &player),
+        };
+        self.commit(frame, changed.into());
+        Ok(())
+    }
+
+    fn kill_player(&mut self, frame: &mut ResponseFrame, username: UserId) -> Result<(), ServerError> {
+        let player = match self.game.players.get(&username) {
+            Some(player) if player.alive => player,
+            _ => return Err(ServerError::Custom("Cannot kill player".to_string(), format!("{} is not a living player", username))),
+        };
+        let role = player.role.clone()
+            .filter(|r| self.roles.get(r).is_some_and(|def| def.reveal_on_death));
+
+        self.commit(frame, KillReveal { user: username, role }.into());
+        Ok(())
+    }
+
+    fn sorted_usernames(&self) -> Vec<UserId> {
+        let mut usernames: Vec<UserId> = self.users.keys().cloned().collect();
+        usernames.sort();
+        usernames
+    }
+
+    /// Logs a mistake of the game script made after the message seq, the admins get it with the next frames
+    fn script_error(&mut self, seq: Option<u64>, error: ServerError) {
+        eprintln!("Script error: {}", error);
+        self.errors.push(LoggedError::new(seq, error));
+    }
+
+    /// Runs a callback from the game script.
+    /// Returns what the callback returned and the frame with everything it wants to change.
+    /// Errors are logged, and sent to the admins with the next response.
+    /// A callback that fails changes nothing, the frame it filled up to then is thrown away.
+    /// Its errors are logged after the message seq, the one that set it off.
+    fn run_callback<A, R>(&mut self, seq: Option<u64>, callback_name: &'static str, args: A) -> (Option<R>, StateFrame)
+    where
+        A: for<'lua> IntoLuaMulti<'lua>,
+        R: for<'lua> FromLuaMulti<'lua> + Default,
+    {
+        let frame = StateFrame::make(self, seq);
+        match &self.lua_state {
+            Some(lua) => {
+                let frame = Arc::new(Mutex::new(frame));
+                let ret = lua.callback(callback_name, frame.clone(), args);
+                let frame = Arc::try_unwrap(frame)
+                    .ok()
+                    .expect("The script frame is only shared during the callback")
+                    .into_inner()
+                    .expect("The script frame should not be poisoned");
+                match ret {
+                    Ok(r) => (Some(r), frame),
+                    Err(e) => {
+                        self.script_error(seq, e);
+                        (None, StateFrame::make(self, seq))
+                    }
+                }
+            }
+            None => (Some(R::default()), frame),
+        }
+    }
+
+    /// The error for a message the game script turned down
+    fn rejected(reason: Option<String>) -> ClientError {
+        ClientError::Custom("Rejected by the game".to_string(), reason.unwrap_or_default())
+    }
+
+    /// Applies the timers and the messages from the game script to the state and the response.
+    fn apply_script(&mut self, frame: &mut ResponseFrame, script: StateFrame) {
+        let seq = script.after;
+        for timer in script.scheduled {
+            self.timers.insert(timer);
+        }
+        for id in script.cancelled {
+            if !self.timers.cancel(id) {
+                self.script_error(seq, ServerError::Custom(
+                    "Cannot cancel timer".to_string(),
+                    format!("The timer {} is not pending", id),
+                ));
+            }
+        }
+
+        for m in script.outbound {
+            match m.data {
+                MessageData::BodyVoteStart(VoteStart { chat_id, subject, end_time }) => {
+                    if let Err(e) = self.open_vote(frame, chat_id, subject, end_time) {
+                        self.script_error(seq, e);
+                    }
+                }
+                MessageData::BodyRoleReveal(RoleReveal { user, role, .. }) => {
+                    if let Err(e) = self.set_role(frame, user, role) {
+                        self.script_error(seq, e);
+                    }
+                }
+                MessageData::BodyActionSelected(selected) => {
+                    let undeclared = selected.action_id.as_ref().filter(|id| !self.actions.contains_key(*id));
+                    if !self.game.players.contains_key(&selected.player) {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot select action".to_string(),
+                            format!("{} is not a player", selected.player),
+                        ));
+                    } else if let Some(id) = undeclared {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot select action".to_string(),
+                            format!("The action {} is not declared", id),
+                        ));
+                    } else {
+                        self.commit(frame, selected.into());
+                    }
+                }
+                MessageData::BodyKillReveal(KillReveal { user, .. }) => {
+                    if let Err(e) = self.kill_player(frame, user) {
+                        self.script_error(seq, e);
+                    }
+                }
+                MessageData::BodyPhaseChange(PhaseChange { phase }) => {
+                    if let Err(e) = self.change_phase(frame, phase) {
+                        self.script_error(seq, e);
+                    }
+                }
+                MessageData::BodyGroupsChanged(GroupsChanged { user, groups, .. }) => {
+                    if let Err(e) = self.set_groups(frame, user, groups.into_iter().collect()) {
+                        self.script_error(seq, e);
+                    }
+                }
+                MessageData::BodyChatSent(chat) => {
+                    if self.game.chats.contains_key(&chat.chat_target) {
+                        self.commit(frame, chat.into());
+                    } else {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot send a message".to_string(),
+                            format!("The chat {} does not exist", chat.chat_target),
+                        ));
+                    }
+                }
+                MessageData::BodySystemMessage(msg) => {
+                    if self.game.players.contains_key(&msg.to) {
+                        self.commit(frame, msg.into());
+                    } else {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot send a message".to_string(),
+                            format!("{} is not a player", msg.to),
+                        ));
+                    }
+                }
+                MessageData::BodyChatCreated(created) => {
+                    if self.game.chats.contains_key(&created.chat.name) {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot create chat".to_string(),
+                            format!("The chat {} already exists", created.chat.name),
+                        ));
+                    } else {
+                        self.commit(frame, created.into());
+                    }
+                }
+                MessageData::BodyChatDeleted(deleted) => {
+                    if self.game.chats.contains_key(&deleted.name) {
+                        self.commit(frame, deleted.into());
+                    } else {
+                        self.script_error(seq, ServerError::Custom(
+                            "Cannot delete chat".to_string(),
+                            format!("The chat {} does not exist", deleted.name),
+                        ));
+                    }
+                }
+                // The rest is either sent by the clients or kept up by the server itself
+                data => self.script_error(seq, ServerError::Custom(
+                    "Cannot send a message".to_string(),
+                    format!("Scripts cannot send '{}' messages", data.to_inner_ref().msg_type()),
+                )),
+            }
+        }
+        self.refresh_actions(frame);
+    }
+
+    pub fn reauth_user(&mut self, token: Uuid, last_seq: Option<u64>, last_rewind: Option<u64>) -> Result<(String,ResponseView), ServerError> {
         let mut frame = ResponseFrame::new(&self.history, 8);
         let uname = if let Some((username, user)) = self.users.iter_mut().find(|u| u.1.uuid == token) {
             if !user.online {
@@ -318,12 +1049,14 @@ impl YapnetState {
             return Err(ServerError::InvalidToken)
         };
 
-        self.successful_login(&mut frame, &uname, token);
+        self.successful_login(&mut frame, &uname, token, last_seq, last_rewind);
+        let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_back", uname.clone());
+        self.apply_script(&mut frame, script);
+        self.outbound.push(frame);
         Ok((uname.clone() ,self.consume_frames()))
     }
     
-    fn successful_login(&mut self, frame: &mut ResponseFrame, username: &String, uuid: Uuid) {
-        let recap = self.recap(username);
+    fn successful_login(&mut self, frame: &mut ResponseFrame, username: &String, uuid: Uuid, last_seq: Option<u64>, last_rewind: Option<u64>) {
         let welcome = Welcome {
             username: username.clone(),
             token: uuid,
@@ -334,91 +1067,85 @@ impl YapnetState {
                     username: username.clone(),
                 }.into(); 
 
+        // The player has to be in the game before the recap, to see the chats it can read
+        self.game.reduce(&player_joined);
+        let recap = self.recap(username, last_seq, last_rewind);
 
         frame.ret(welcome);
         frame.ret_all(recap); 
-        frame.broadcast_ex(player_joined, "system:all".to_string());
+        // The player gets its own join too, after the recap, so its game folds the same as ours
+        frame.broadcast(player_joined);
+        self.refresh_actions(frame);
     }
     
-    fn recap(&self, username: &String) -> Vec<MessageV2Enum>{
-        let mut out = Vec::new();
-        let mut mbuf = Vec::new();
-        let mut start_cursor = 0;
-        let mut chunks = 0;
-
-        out.push(RecapHead {count: 0 , chunk_sz: Self::RECAP_CHUNK_SZ}.into()); 
-        for m in self.history.iter() {
-            if self.user_can_view(m, username) {
-                if mbuf.len() < Self::RECAP_CHUNK_SZ {
-                    mbuf.push(m);
-                } else {
-                    chunks += 1;
-
-                    out.push(RecapTail {
-                            start: start_cursor,
-                            msgs: mbuf
-                                .iter()
-                                .map(|m| serde_json::to_value(m).unwrap())
-                                .collect(),
-                            }.into());
-
-                    start_cursor += mbuf.len();
-                    mbuf = Vec::new();
-                }
-            }
-        }
-        // Append at the end
-        if !mbuf.is_empty() {
-            chunks += 1;
+    /// Sends the recap again to a client that missed messages
+    pub fn resync(&mut self, username: &String, last_seq: Option<u64>, last_rewind: Option<u64>) -> ResponseView {
+        let mut frame = ResponseFrame::new(&self.history, 1);
+        frame.ret_all(self.recap(username, last_seq, last_rewind));
+        self.outbound.push(frame);
+        self.consume_frames()
+    }
+
+    /// Packs the messages the user can see into chunks, only the ones after last_seq if it is given.
+    /// A client that went through the last rewind only gets the rewind again before what it missed,
+    /// one that did not might have messages the rewind took back, so it gets everything from the rewind.
+    fn recap(&self, username: &String, last_seq: Option<u64>, client_rewind: Option<u64>) -> Vec<MessageV2Enum>{
+        let last_rewind = self.history
+            .iter()
+            .filter(|m| matches!(m.data, MessageData::BodyRewind(_)))
+            .map(|m| m.seq)
+            .last();
+        let (from, marker) = match (last_seq, last_rewind) {
+            (None, _) => (0, None),
+            (Some(seq), Some(rewind)) if seq >= rewind && client_rewind == Some(rewind) => (seq + 1, Some(rewind)),
+            (Some(seq), Some(rewind)) => ((seq + 1).min(rewind), None),
+            (Some(seq), None) => (seq + 1, None),
+        };
+
+        let visible: Vec<serde_json::Value> = self.history
+            .iter()
+            .filter(|m| (m.seq >= from || Some(m.seq) == marker) && self.user_can_view(m, username))
+            .map(|m| serde_json::to_value(m).expect("Messages always serialize"))
+            .collect();
+
+        let mut out = vec![RecapHead { count: visible.len(), chunk_sz: Self::RECAP_CHUNK_SZ }.into()];
+        let chunk_count = visible.len().div_ceil(Self::RECAP_CHUNK_SZ).max(1);
+        let mut chunks = visible.chunks(Self::RECAP_CHUNK_SZ);
+        for index in 0..chunk_count {
             out.push(RecapTail {
-                    start: start_cursor,
-                    msgs: mbuf
-                        .iter()
-                        .map(|m| serde_json::to_value(m).unwrap())
-                        .collect(),
-                    }.into());
-        }
-       
-        // Accessing the item in the array, praying for the compiler to just get the first element
-        // and change that one variable.
-        match &mut out.get_mut(0).expect("We just pushed") {
-            MessageV2Enum::BodyRecapHead(h) => {h.count = chunks}
-            _ => unreachable!(),
-        }
-        
+                index,
+                start: index * Self::RECAP_CHUNK_SZ,
+                last: index + 1 == chunk_count,
+                msgs: chunks.next().unwrap_or_default().to_vec(),
+            }.into());
+        }
         out
     }
     
-    fn user_can_view(&self, msg: &Message, username: &String) -> bool {
+    /// Decides if a user gets to see a message, both live and in the recap, based on its scope.
+    pub fn user_can_view(&self, msg: &Message, username: &UserId) -> bool {
         let obj = msg.data.to_inner_ref();
 
-        if obj.is_global() {
-            return true;
-        } else if let Some(uname) = obj.subject() {
-            if uname == *username {
-                return true;
-            }
-        } else if let Some(chatn) = obj.chat() {
-            if let Some(ch) = self.chats.get(&chatn) {
-                if ch.can_read(
-                    self.users
-                        .get(username)
-                        .expect("Assumed that the user exists if their visibility is checked."),
-                ) {
-                    return true;
-                }
+        match obj.scope() {
+            Scope::Global => true,
+            Scope::Chat => {
+                let (Some(player), Some(chatn)) = (self.game.players.get(username), obj.chat()) else {
+                    return false;
+                };
+                self.game.chats.get(&chatn).is_some_and(|ch| ch.can_read(username, player))
             }
+            Scope::Private => obj.object().or_else(|| obj.subject()).as_ref() == Some(username),
+            Scope::Server => false,
         }
-        true
     }
 
-    pub fn return_response(&mut self, msg: MessageV2Enum) -> ResponseView<'_> {
+    pub fn return_response(&mut self, msg: MessageV2Enum) -> ResponseView {
         let mut frame = ResponseFrame::new(&self.history, 1); 
         frame.ret(msg);
         self.consume_frames() 
     }
 
-    pub fn new_user<'a>(&'a mut self, username: &String) ->  Result<ResponseView<'a>, ServerError> {
+    pub fn new_user(&mut self, username: &String) ->  Result<ResponseView, ServerError> {
         if self.users.contains_key(username) {
             return Err(ServerError::NameTaken(username.clone())); 
         }
@@ -428,38 +1155,700 @@ impl YapnetState {
         let mut frame = ResponseFrame::new(&self.history, 8);
 
         self.users.insert(username.clone(), user);
-        self.successful_login(&mut frame, username, token);
+        self.successful_login(&mut frame, username, token, None, None);
+        let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_join", username.clone());
+        self.apply_script(&mut frame, script);
         self.outbound.push(frame);
         Ok(self.consume_frames())
     }
-    pub fn player_leave<'a>(&'a mut self, userc: &String) -> Result<ResponseView<'a>,ServerError> {
+    pub fn player_leave(&mut self, userc: &String) -> Result<ResponseView,ServerError> {
         if let Some(user) = self.users.get_mut(userc) {
             let mut frame = ResponseFrame::new(&self.history,1);
             user.online = false;
-            frame.broadcast(PlayerLeft {
+            self.commit(&mut frame, PlayerLeft {
                     username: userc.clone(),
-                }.into(),
-                    "system:all".to_string(),
-                );
+                }.into());
+            let (_, script) = self.run_callback::<_, ()>(frame.last_seq(), "on_leave", userc.clone());
+            self.apply_script(&mut frame, script);
+            self.outbound.push(frame);
             Ok(self.consume_frames())
         } else {
             Err(ServerError::AlreadyJoinedOrLeft)
         }
     }
 
-    pub fn push_setup_message(&mut self) {
-        self.history.state_message(Setup {
-            chats: self.chats.iter().map(|(name, chat)| {
-               ChatSetup {
-                    name: name.clone(),
-                    perm: chat.perms.clone(),
-                }  
-            }).collect(),
-        }.into());
-
+    /// Starts the history with the chats of the game
+    pub fn push_setup_message(&mut self, chats: Vec<ChatSetup>) {
+        let setup: MessageData = Setup { chats }.into();
+        self.game.reduce(&setup);
+        self.history.state_message(setup);
     }
 
     pub fn print_messages(&self) {
         self.history.print_state();
     }
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{recap::RecapReader, Perms};
+
+    fn mafia_game() -> YapnetState {
+        let mut state = YapnetState::new();
+        let perms = Perms::wrap_vec(vec![Perm::Group {
+            rw: 3,
+            name: "mafia".to_string(),
+        }]);
+        state.push_setup_message(vec![ChatSetup {
+            name: "mafia".to_string(),
+            perm: perms,
+            phases: vec![],
+        }]);
+        for name in ["alice", "bob"] {
+            state.new_user(&name.to_string()).unwrap();
+        }
+        let mut frame = ResponseFrame::new(&state.history, 1);
+        state.set_groups(&mut frame, "bob".to_string(), BTreeSet::from(["mafia".to_string()])).unwrap();
+        state.outbound.push(frame);
+        state.consume_frames();
+        state
+    }
+
+    fn send_chat(state: &mut YapnetState, sender: &str, chat: &str) -> Vec<(YapnetResponse, Message)> {
+        let msg = ChatSend {
+            chat_target: chat.to_string(),
+            chat_content: "hello".to_string(),
+        }
+        .into_message();
+        state
+            .handle_message_serveir(&sender.to_string(), msg)
+            .iter()
+            .map(|(r, m)| (r.clone(), m.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn outsider_cannot_read_mafia_chat() {
+        let state = mafia_game();
+        let chat = state.game.chats.get("mafia").unwrap();
+        let alice = "alice".to_string();
+        let bob = "bob".to_string();
+
+        assert!(!chat.can_read(&alice, state.game.players.get(&alice).unwrap()));
+        assert!(chat.can_read(&bob, state.game.players.get(&bob).unwrap()));
+    }
+
+    #[test]
+    fn game_is_a_fold_of_the_history() {
+        let mut state = mafia_game();
+        let mut frame = ResponseFrame::new(&state.history, 1);
+        state.set_groups(&mut frame, "alice".to_string(), BTreeSet::from(["mafia".to_string()])).unwrap();
+        state.kill_player(&mut frame, "bob".to_string()).unwrap();
+        state.outbound.push(frame);
+        state.consume_frames();
+        state.player_leave(&"alice".to_string()).unwrap();
+
+        let folded = Game::fold(state.history.iter());
+        assert_eq!(folded.players, state.game.players);
+        assert_eq!(folded.phase, state.game.phase);
+        assert!(!folded.players["bob"].alive);
+        assert!(!folded.players["alice"].online);
+        assert!(folded.players["alice"].groups.contains("mafia"));
+    }
+
+    #[test]
+    fn outsider_cannot_post_in_mafia_chat() {
+        let mut state = mafia_game();
+        let responses = send_chat(&mut state, "alice", "mafia");
+
+        assert_eq!(responses.len(), 1);
+        match &responses[0] {
+            (YapnetResponse::Return(_), Message { data: MessageData::BodyYnError(e), .. }) => {
+                assert_eq!(e.kind, "NoPermission")
+            }
+            x => panic!("Expected a NoPermission error, got {:?}", x),
+        }
+        assert!(!state.history.iter().any(|m| matches!(m.data, MessageData::BodyChatSent(_))));
+    }
+
+    #[test]
+    fn member_can_post_in_mafia_chat() {
+        let mut state = mafia_game();
+        let responses = send_chat(&mut state, "bob", "mafia");
+
+        assert_eq!(responses.len(), 1);
+        match &responses[0] {
+            (YapnetResponse::Broadcast(_), Message { data: MessageData::BodyChatSent(c), .. }) => {
+                assert_eq!(c.chat_target, "mafia");
+                assert_eq!(c.chat_sender, "bob");
+            }
+            x => panic!("Expected the chat to be broadcast, got {:?}", x),
+        }
+    }
+
+    fn recap_contains(state: &YapnetState, username: &str, msg_type: &str) -> bool {
+        state.recap(&username.to_string(), None, None).iter().any(|m| match m {
+            MessageData::BodyRecapTail(tail) => tail.msgs.iter().any(|v| v["msg_type"] == msg_type),
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn global_messages_are_visible_to_everyone() {
+        let mut state = mafia_game();
+        let msg = state.history.state_message(PlayerJoined { username: "bob".to_string() }.into()).clone();
+
+        assert!(state.user_can_view(&msg, &"alice".to_string()));
+        assert!(state.user_can_view(&msg, &"bob".to_string()));
+        assert!(state.user_can_view(&msg, &"nobody".to_string()));
+    }
+
+    #[test]
+    fn subject_messages_are_only_visible_to_the_subject() {
+        let mut state = mafia_game();
+        let msg = state.history.state_message(
+            AvailableActions {
+                player: "bob".to_string(),
+                actions: vec![],
+            }
+            .into(),
+        ).clone();
+
+        assert!(state.user_can_view(&msg, &"bob".to_string()));
+        assert!(!state.user_can_view(&msg, &"alice".to_string()));
+        assert!(recap_contains(&state, "bob", "aavl"));
+        assert!(!recap_contains(&state, "alice", "aavl"));
+    }
+
+    #[test]
+    fn chat_messages_are_only_visible_to_readers() {
+        let mut state = mafia_game();
+        send_chat(&mut state, "bob", "mafia");
+        let msg = state.history.iter().last().unwrap().clone();
+
+        assert!(state.user_can_view(&msg, &"bob".to_string()));
+        assert!(!state.user_can_view(&msg, &"alice".to_string()));
+        assert!(recap_contains(&state, "bob", "chat"));
+        assert!(!recap_contains(&state, "alice", "chat"));
+    }
+
+    fn scripted_game(script: &str) -> YapnetState {
+        let lua = mlua::Lua::new();
+        lua.load(script).exec().unwrap();
+        let mut state = crate::lua::state_init(lua).unwrap();
+        for name in ["alice", "bob"] {
+            state.new_user(&name.to_string()).unwrap();
+        }
+        state
+    }
+
+    #[test]
+    fn scripts_hear_chat_and_answer_through_the_history() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_chat = function (frame, target, sender, content)
+                    frame:send_message({ msg_type = "chat", data = {
+                        chat_sender = "SYSTEM", chat_target = target, chat_content = sender .. ": " .. content,
+                    }})
+                    frame:send_message({ msg_type = "welc", data = { username = sender, token = "00000000-0000-0000-0000-000000000000" }})
+                end,
+            }
+        "#);
+        let sent = send_chat(&mut state, "alice", "town");
+
+        let replies: Vec<ChatSent> = sent
+            .into_iter()
+            .filter_map(|(_, m)| m.data.try_into().ok())
+            .collect();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].chat_sender, "SYSTEM");
+        assert_eq!(replies[1].chat_content, "alice: hello");
+        assert!(!state.history.iter().any(|m| matches!(m.data, MessageData::BodyWelcome(_))));
+    }
+
+    #[test]
+    fn scripts_can_reject_or_rewrite_chat() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_chat = function (frame, target, sender, content)
+                    if sender == "bob" then
+                        return false, "bob is muted"
+                    end
+                    return content .. "!"
+                end,
+            }
+        "#);
+
+        let rejected = send_chat(&mut state, "bob", "town");
+        assert!(rejected.iter().all(|(r, _)| matches!(r, YapnetResponse::Return(_))));
+        let error: YnError = rejected[0].1.data.clone().try_into().unwrap();
+        assert_eq!(error.details, "bob is muted");
+
+        let rewritten = send_chat(&mut state, "alice", "town");
+        let chat: ChatSent = rewritten[0].1.data.clone().try_into().unwrap();
+        assert_eq!(chat.chat_content, "hello!");
+    }
+
+    fn read_recap(recap: Vec<MessageData>) -> Vec<Message> {
+        let mut recap = recap.into_iter();
+        let Some(MessageData::BodyRecapHead(head)) = recap.next() else {
+            panic!("The recap starts with a head");
+        };
+        let mut reader = RecapReader::new(&head);
+        let mut out = vec![];
+        for m in recap {
+            let MessageData::BodyRecapTail(tail) = m else {
+                panic!("Only chunks follow the head");
+            };
+            out.extend(reader.read(&tail).unwrap());
+        }
+        assert!(reader.is_done());
+        out
+    }
+
+    #[test]
+    fn recap_round_trip_rebuilds_the_visible_history() {
+        let mut state = mafia_game();
+        for _ in 0..(YapnetState::RECAP_CHUNK_SZ * 2) {
+            send_chat(&mut state, "bob", "mafia");
+        }
+
+        for username in ["alice", "bob"].map(String::from) {
+            let visible: Vec<u64> = state.history
+                .iter()
+                .filter(|m| state.user_can_view(m, &username))
+                .map(|m| m.seq)
+                .collect();
+            let received: Vec<u64> = read_recap(state.recap(&username, None, None))
+                .iter()
+                .map(|m| m.seq)
+                .collect();
+            assert_eq!(received, visible);
+        }
+    }
+
+    #[test]
+    fn joining_players_get_their_own_join_after_the_recap() {
+        let mut state = mafia_game();
+        let view = state.new_user(&"carol".to_string()).unwrap();
+
+        let sent: Vec<_> = view.iter().collect();
+        let recap_end = sent.iter().rposition(|(_, m)| matches!(m.data, MessageData::BodyRecapTail(_))).unwrap();
+        let joined = sent.iter().position(|(r, m)| {
+            matches!(r, YapnetResponse::Broadcast(_))
+                && matches!(&m.data, MessageData::BodyPlayerJoined(PlayerJoined { username }) if username == "carol")
+        });
+        assert!(joined.is_some_and(|i| i > recap_end));
+    }
+
+    #[test]
+    fn empty_recap_still_ends() {
+        let state = mafia_game();
+        let last_seq = state.history.next_seq() - 1;
+
+        assert!(read_recap(state.recap(&"alice".to_string(), Some(last_seq), None)).is_empty());
+    }
+
+    fn recap_seqs(state: &YapnetState, username: &str, last_seq: Option<u64>, last_rewind: Option<u64>) -> Vec<u64> {
+        state.recap(&username.to_string(), last_seq, last_rewind).iter().flat_map(|m| match m {
+            MessageData::BodyRecapTail(tail) => tail.msgs.iter().map(|v| v["seq"].as_u64().unwrap()).collect(),
+            _ => vec![],
+        }).collect()
+    }
+
+    #[test]
+    fn recap_after_last_seq_only_has_newer_messages() {
+        let mut state = mafia_game();
+        let last_seq = state.history.next_seq() - 1;
+        state.new_user(&"carol".to_string()).unwrap();
+
+        let seqs = recap_seqs(&state, "bob", Some(last_seq), None);
+        assert!(!seqs.is_empty());
+        assert!(seqs.iter().all(|s| *s > last_seq));
+        assert_eq!(recap_seqs(&state, "bob", None, None).first(), Some(&0));
+    }
+
+    #[test]
+    fn recap_after_last_seq_resends_from_the_rewind() {
+        let mut state = mafia_game();
+        let rewind = state.history.next_seq() - 1;
+        state.rewind_history(rewind);
+        state.consume_frames();
+        state.new_user(&"carol".to_string()).unwrap();
+        let last_seq = state.history.next_seq() - 1;
+
+        assert_eq!(recap_seqs(&state, "bob", Some(last_seq), None).first(), Some(&rewind));
+        assert!(recap_seqs(&state, "bob", Some(last_seq), None).len() > 1);
+    }
+
+    #[test]
+    fn recap_after_the_rewind_only_has_the_rewind_and_newer_messages() {
+        let mut state = mafia_game();
+        let rewind = state.history.next_seq() - 1;
+        state.rewind_history(rewind);
+        state.consume_frames();
+        state.new_user(&"carol".to_string()).unwrap();
+        let last_seq = state.history.next_seq() - 1;
+        state.new_user(&"dave".to_string()).unwrap();
+
+        let seqs = recap_seqs(&state, "bob", Some(last_seq), Some(rewind));
+        assert_eq!(seqs.first(), Some(&rewind));
+        assert!(seqs.iter().skip(1).all(|s| *s > last_seq));
+        assert!(seqs.len() > 1);
+        // The seqs of a client that did not see this rewind might be from before it
+        assert_eq!(recap_seqs(&state, "bob", Some(last_seq), Some(rewind - 1)), recap_seqs(&state, "bob", Some(rewind - 1), None));
+    }
+
+    const TOWN_SCRIPT: &str = r#"__game = { chats = { town = { allowed = "any" } } }"#;
+
+    fn reload_with(state: &mut YapnetState, script: &str) -> Result<ResponseView, ServerError> {
+        let lua = mlua::Lua::new();
+        lua.load(script).exec().unwrap();
+        state.reload(lua)
+    }
+
+    #[test]
+    fn reload_adds_new_chats_and_keeps_the_game() {
+        let mut state = scripted_game(TOWN_SCRIPT);
+        send_chat(&mut state, "alice", "town");
+        let before = state.history.next_seq();
+
+        let view = reload_with(&mut state, r#"
+            __game = {
+                chats = { town = { allowed = "none" }, graveyard = { allowed = "any" } },
+                on_chat = function () return false, "muted" end,
+            }
+        "#).unwrap();
+
+        let created: Vec<ChatCreated> = view.iter().filter_map(|(_, m)| m.data.clone().try_into().ok()).collect();
+        assert_eq!(created.len(), 1);
+        assert_eq!(created[0].chat.name, "graveyard");
+        assert_eq!(state.history.next_seq(), before + 1);
+        // The chat that was already there keeps its permissions
+        assert!(state.game.chats["town"].can_write(&"alice".to_string(), &state.game.players["alice"]));
+        assert_eq!(state.game.players.len(), 2);
+
+        let sent = send_chat(&mut state, "alice", "graveyard");
+        assert!(matches!(sent[0].1.data, MessageData::BodyYnError(_)));
+    }
+
+    #[test]
+    fn broken_reload_keeps_the_old_script() {
+        let mut state = scripted_game(TOWN_SCRIPT);
+        assert!(reload_with(&mut state, "game = {}").is_err());
+        assert!(state.game.chats.contains_key("town"));
+        let sent = send_chat(&mut state, "alice", "town");
+        assert!(matches!(sent[0].1.data, MessageData::BodyChatSent(_)));
+    }
+
+    #[test]
+    fn only_admins_can_reload() {
+        let path = std::env::temp_dir().join(format!("yapnet-reload-{}.lua", Uuid::new_v4()));
+        std::fs::write(&path, r#"return { chats = { town = { allowed = "any" }, graveyard = { allowed = "any" } } }"#).unwrap();
+        let mut state = scripted_game(TOWN_SCRIPT);
+        state.script = Some(path.clone());
+        let reload = |state: &mut YapnetState| {
+            state.handle_message_serveir(&"alice".to_string(), Reload {}.into_message())
+        };
+
+        let view = reload(&mut state);
+        assert!(matches!(view.iter().next().unwrap().1.data, MessageData::BodyYnError(_)));
+        assert!(!state.game.chats.contains_key("graveyard"));
+
+        let mut frame = ResponseFrame::new(&state.history, 1);
+        state.set_groups(&mut frame, "alice".to_string(), BTreeSet::from([crate::models::game::ADMIN_GROUP.to_string()])).unwrap();
+        state.outbound.push(frame);
+        state.consume_frames();
+        reload(&mut state);
+        std::fs::remove_file(path).unwrap();
+        assert!(state.game.chats.contains_key("graveyard"));
+    }
+
+    fn limited_callback(script: &str, limits: crate::lua::LuaLimits) -> Result<(), ServerError> {
+        let mut state = scripted_game(script);
+        let lua_state = state.lua_state.as_ref().unwrap();
+        crate::lua::set_limits(&lua_state.lua, limits).unwrap();
+        let frame = Arc::new(Mutex::new(StateFrame::make(&state, None)));
+        let res = lua_state.callback::<_, ()>("runaway", frame, ());
+
+        // The state keeps going after the callback is aborted
+        assert!(matches!(send_chat(&mut state, "alice", "town")[0].1.data, MessageData::BodyChatSent(_)));
+        res
+    }
+
+    #[test]
+    fn runaway_callbacks_are_aborted() {
+        let res = limited_callback(
+            r#"__game = { chats = { town = { allowed = "any" } }, runaway = function () while true do end end }"#,
+            crate::lua::LuaLimits { instructions: 10_000, ..Default::default() },
+        );
+        assert!(matches!(res, Err(ServerError::ScriptLimit(name, _)) if name == "runaway"));
+    }
+
+    #[test]
+    fn aborted_callbacks_leave_no_trace() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_chat = function (frame, target)
+                    frame:set_phase("night")
+                    while true do
+                        frame:send_message({ msg_type = "chat", data = {
+                            chat_sender = "SYSTEM", chat_target = target, chat_content = "spam",
+                        }})
+                    end
+                end,
+            }
+        "#);
+        let lua = &state.lua_state.as_ref().unwrap().lua;
+        crate::lua::set_limits(lua, crate::lua::LuaLimits { instructions: 100_000, ..Default::default() }).unwrap();
+        let before = state.history.next_seq();
+
+        send_chat(&mut state, "alice", "town");
+
+        // Only the chat message itself made it
+        assert_eq!(state.history.next_seq(), before + 1);
+        assert!(!state.history.iter().any(|m| matches!(&m.data, MessageData::BodyChatSent(c) if c.chat_content == "spam")));
+        assert_eq!(state.game.phase, Phase::Lobby);
+        assert!(matches!(state.errors.iter().next().unwrap().error, ServerError::ScriptLimit(..)));
+    }
+
+    #[test]
+    fn callbacks_cannot_take_all_the_memory() {
+        let res = limited_callback(
+            r#"__game = {
+                chats = { town = { allowed = "any" } },
+                runaway = function ()
+                    local t = {}
+                    while true do t[#t + 1] = ("x"):rep(1024) .. #t end
+                end,
+            }"#,
+            crate::lua::LuaLimits { memory: 1024 * 1024, ..Default::default() },
+        );
+        assert!(matches!(res, Err(ServerError::ScriptLimit(name, _)) if name == "runaway"));
+    }
+
+    #[test]
+    fn script_errors_are_logged_and_sent_to_the_admins() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_chat = function () error("boom") end,
+            }
+        "#);
+        let last = state.history.next_seq() - 1;
+        let sent = send_chat(&mut state, "alice", "town");
+
+        let errors: Vec<YnError> = sent
+            .iter()
+            .filter(|(r, _)| matches!(r, YapnetResponse::Admins(_)))
+            .filter_map(|(_, m)| m.data.clone().try_into().ok())
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, "ScriptError");
+        assert!(errors[0].info.contains("on_chat") && errors[0].info.contains("boom"));
+        assert!(errors[0].info.contains(&format!("after message {}", last)));
+        assert!(errors[0].details.contains("stack traceback"));
+
+        assert_eq!(state.errors.len(), 1);
+        assert_eq!(state.errors.iter().next().unwrap().seq, Some(last));
+        // Errors are only sent once
+        let sent = send_chat(&mut state, "alice", "town");
+        assert_eq!(sent.iter().filter(|(r, _)| matches!(r, YapnetResponse::Admins(_))).count(), 1);
+        assert_eq!(state.errors.len(), 2);
+    }
+
+    #[test]
+    fn script_mistakes_are_sent_to_the_admins() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_chat = function (frame)
+                    frame:send_message({ msg_type = "chat", data = {
+                        chat_sender = "SYSTEM", chat_target = "nowhere", chat_content = "hello",
+                    }})
+                end,
+            }
+        "#);
+        // on_chat runs before the chat is committed
+        let last = state.history.next_seq() - 1;
+        let sent = send_chat(&mut state, "alice", "town");
+
+        let errors: Vec<YnError> = sent
+            .iter()
+            .filter(|(r, _)| matches!(r, YapnetResponse::Admins(_)))
+            .filter_map(|(_, m)| m.data.clone().try_into().ok())
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].info, format!("Cannot send a message (after message {})", last));
+        assert!(errors[0].details.contains("nowhere"));
+        assert_eq!(state.errors.len(), 1);
+    }
+
+    #[test]
+    fn vote_result_errors_point_at_the_result() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                on_vote_result = function () error("boom") end,
+            }
+        "#);
+        state.start_vote("town".to_string(), "lynch".to_string(), Utc::now()).unwrap();
+        state.tick();
+
+        let result = state
+            .history
+            .iter()
+            .find(|m| matches!(m.data, MessageData::BodyVoteResult(_)))
+            .map(|m| m.seq);
+        assert!(result.is_some());
+        assert_eq!(state.errors.len(), 1);
+        assert_eq!(state.errors.iter().next().unwrap().seq, result);
+    }
+
+    #[test]
+    fn chats_can_have_separate_read_and_write_rules() {
+        let state = scripted_game(r#"
+            __game = {
+                chats = {
+                    news = { read = { "any" }, write = { "user:alice" } },
+                    graveyard = { rules = { { group = "dead", rw = 3 }, { any = true, rw = 1 } } },
+                },
+            }
+        "#);
+        let access = |chat: &str, user: &str| {
+            state.game.chats[chat].access(&user.to_string(), &state.game.players[user])
+        };
+        assert_eq!(access("news", "alice"), 3);
+        assert_eq!(access("news", "bob"), 1);
+        assert_eq!(access("graveyard", "alice"), 1);
+    }
+
+    #[test]
+    fn dead_players_cannot_act() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                actions = { inspect = { args = { "player" } } },
+                on_action = function () heard = true end,
+            }
+        "#);
+        let mut frame = ResponseFrame::new(&state.history, 1);
+        state.kill_player(&mut frame, "bob".to_string()).unwrap();
+        state.outbound.push(frame);
+        state.consume_frames();
+
+        let msg = SubmitAction { action_id: "inspect".to_string(), args: vec!["alice".to_string()] }.into_message();
+        let view = state.handle_message_serveir(&"bob".to_string(), msg);
+        let errors: Vec<YnError> = view.iter().filter_map(|(_, m)| m.data.clone().try_into().ok()).collect();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, "NoPermission");
+        assert!(state.game.players["bob"].current_action.is_none());
+        let heard: Option<bool> = state.lua_state.as_ref().unwrap().lua.globals().get("heard").unwrap();
+        assert_eq!(heard, None);
+    }
+
+    #[test]
+    fn picked_actions_are_kept_in_the_history() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                phases = { "dusk" },
+                actions = { inspect = { args = { "player" } } },
+            }
+        "#);
+        let msg = SubmitAction { action_id: "inspect".to_string(), args: vec!["bob".to_string()] }.into_message();
+        let view = state.handle_message_serveir(&"alice".to_string(), msg);
+
+        let picked = Some(("inspect".to_string(), vec!["bob".to_string()]));
+        let selected: Vec<ActionSelected> = view.iter().filter_map(|(_, m)| m.data.clone().try_into().ok()).collect();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(state.game.players["alice"].current_action, picked);
+        assert_eq!(Game::fold(state.history.iter()).players["alice"].current_action, picked);
+        // Only alice sees what she picked
+        let last = state.history.iter().last().unwrap().clone();
+        assert!(!state.user_can_view(&last, &"bob".to_string()));
+
+        state.set_phase(Phase::Custom("dusk".to_string())).unwrap();
+        assert_eq!(state.game.players["alice"].current_action, None);
+    }
+
+    #[test]
+    fn phase_hooks_that_switch_back_and_forth_stop() {
+        let mut state = scripted_game(r#"
+            __game = {
+                chats = { town = { allowed = "any" } },
+                phases = { "dusk", "dawn" },
+                on_phase_enter = function (frame, phase)
+                    entered = (entered or 0) + 1
+                    if phase == "dusk" then frame:set_phase("dawn") else frame:set_phase("dusk") end
+                end,
+            }
+        "#);
+        state.set_phase(Phase::Custom("dusk".to_string())).unwrap();
+
+        let entered: usize = state.lua_state.as_ref().unwrap().lua.globals().get("entered").unwrap();
+        assert_eq!(entered, YapnetState::MAX_CHAINED_PHASES + 1);
+        assert!(!state.changing_phase);
+        assert!(state.queued_phase.is_none());
+        // The phase changes were done one after the other, not inside each other
+        let phases: Vec<Phase> = state
+            .history
+            .iter()
+            .filter_map(|m| match &m.data {
+                MessageData::BodyPhaseChange(PhaseChange { phase }) => Some(phase.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(phases.len(), entered);
+        assert_eq!(state.game.phase, *phases.last().unwrap());
+    }
+
+    #[test]
+    fn idle_ticks_do_not_write_the_snapshot() {
+        let dir = std::env::temp_dir().join(format!("yapnet-tick-{}", Uuid::new_v4()));
+        let mut state = scripted_game(TOWN_SCRIPT);
+        state.start_journal(&dir, false).unwrap();
+        let snapshot = dir.join("snapshot.json");
+        std::fs::remove_file(&snapshot).unwrap();
+
+        let view = state.tick();
+        let written = snapshot.exists();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert!(view.iter().next().is_none());
+        assert!(!written);
+    }
+
+    #[test]
+    fn the_journal_gets_each_message_once() {
+        let dir = std::env::temp_dir().join(format!("yapnet-journal-{}", Uuid::new_v4()));
+        let mut state = scripted_game(TOWN_SCRIPT);
+        state.start_journal(&dir, false).unwrap();
+        send_chat(&mut state, "alice", "town");
+        send_chat(&mut state, "bob", "town");
+
+        let (_, messages, _) = Journal::open(&dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        let saved: Vec<u64> = messages.iter().map(|m| m.seq).collect();
+        let kept: Vec<u64> = state.history.iter().map(|m| m.seq).collect();
+        assert_eq!(saved, kept);
+    }
+
+    #[test]
+    fn a_saved_game_is_only_replaced_with_overwrite() {
+        let dir = std::env::temp_dir().join(format!("yapnet-save-{}", Uuid::new_v4()));
+        scripted_game(TOWN_SCRIPT).start_journal(&dir, false).unwrap();
+        let journal = std::fs::read(dir.join("journal.jsonl")).unwrap();
+
+        let refused = scripted_game(TOWN_SCRIPT).start_journal(&dir, false);
+        let kept = std::fs::read(dir.join("journal.jsonl")).unwrap();
+        let replaced = scripted_game(TOWN_SCRIPT).start_journal(&dir, true);
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(refused.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(kept, journal);
+        assert!(replaced.is_ok());
+    }
+}
@@ -24,21 +24,6 @@ fn get_lit_string(e: Expr) -> syn::Result<String> {
         )),
     }
 }
-fn get_lit_bool(e: Expr) -> syn::Result<bool> {
-    match e {
-        Expr::Lit(l) => match l.lit {
-            syn::Lit::Bool(s) => Ok(s.value),
-            _ => Err(syn::Error::new_spanned(
-                l,
-                "This should be a boolean literal",
-            )),
-        },
-        _ => Err(syn::Error::new_spanned(
-            e,
-            "This should be a boolean literal",
-        )),
-    }
-}
 
 fn handle_missing_arg<T>(opt: Option<T>, span: Span, name: &str) -> syn::Result<T> {
     match opt {
@@ -50,7 +35,7 @@ fn handle_missing_arg<T>(opt: Option<T>, span: Span, name: &str) -> syn::Result<
 #[darling(default, attributes(msg_data), forward_attrs(allow, doc, cfg))]
 struct OuterOpts {
     msg_type: String,
-    global: bool,
+    scope: String,
 }
 
 #[derive(FromMeta, Default)]
@@ -71,7 +56,7 @@ struct InnerIdent {
 impl Parse for OuterOpts {
     fn parse(input: ParseStream) -> syn::Result<Self> {
         let mut msg_type = None;
-        let mut global = None;
+        let mut scope = None;
 
         loop {
             if input.peek(End) {
@@ -88,14 +73,14 @@ impl Parse for OuterOpts {
 
                 match mv.path.segments.last().unwrap().ident.to_string().as_str() {
                     "msg_type" => msg_type = Some(get_lit_string(mv.value)?),
-                    "global" => global = Some(get_lit_bool(mv.value)?),
+                    "scope" => scope = Some(get_lit_string(mv.value)?),
                     _ => return Err(syn::Error::new_spanned(mv, "Foreign name_value")),
                 }
             }
         }
         Ok(Self {
             msg_type: handle_missing_arg(msg_type, input.span(), stringify!(msg_type))?,
-            global: handle_missing_arg(global, input.span(), stringify!(global))?,
+            scope: handle_missing_arg(scope, input.span(), stringify!(scope))?,
         })
     }
 }
@@ -111,6 +96,32 @@ fn return_field_opt(ident: Option<Ident>) -> proc_macro2::TokenStream {
     }
 }
 
+/// Picks the Scope variant, checking that the message has the fields the scope routes on
+fn scope_variant(scope: &str, idents: &InnerIdent) -> Result<Ident, String> {
+    let variant = match scope {
+        "global" => "Global",
+        "chat" if idents.chat.is_none() => {
+            return Err("A chat scoped message needs a #[msg_info(chat)] field".to_string())
+        }
+        "chat" => "Chat",
+        "private" if idents.subject.is_none() && idents.object.is_none() => {
+            return Err(
+                "A private message needs a #[msg_info(subject)] or #[msg_info(object)] field"
+                    .to_string(),
+            )
+        }
+        "private" => "Private",
+        "server" => "Server",
+        x => {
+            return Err(format!(
+                "Unknown scope '{}', expected global, chat, private or server",
+                x
+            ))
+        }
+    };
+    Ok(Ident::new(variant, Span::call_site()))
+}
+
 #[proc_macro_derive(MessageDataV2, attributes(msg_data, msg_info))]
 pub fn derive_message_data(_item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(_item);
@@ -161,8 +172,13 @@ pub fn derive_message_data(_item: TokenStream) -> TokenStream {
 
     // println!("Deriving MessageDataV2 for {}", ident);
 
+    let scope = match scope_variant(&opts.scope, &idents) {
+        Ok(s) => s,
+        Err(err) => {
+            return syn::Error::new_spanned(ident, err).to_compile_error().into();
+        }
+    };
     let msg_type = opts.msg_type;
-    let global = opts.global;
     let subject = return_field_opt(idents.subject);
     let object = return_field_opt(idents.object);
     let chat = return_field_opt(idents.chat);
@@ -170,7 +186,7 @@ pub fn derive_message_data(_item: TokenStream) -> TokenStream {
     let output = quote! {
         impl crate::protocol::MessageDataV2 for #ident {
             fn msg_type(&self) -> &'static str { #msg_type }
-            fn is_global(&self) -> bool { #global }
+            fn scope(&self) -> crate::protocol::Scope { crate::protocol::Scope::#scope }
 
             fn subject(&self) -> Option<crate::protocol::UserId> { #subject }
             fn object(&self)  -> Option<crate::protocol::UserId> { #object }
@@ -274,7 +290,6 @@ impl Parse for ProtocolBody {
 pub fn protocol_body(module: TokenStream) -> TokenStream {
     let m0 = module.clone();
     let input: ProtocolBody = parse_macro_input!(m0);
-    let m1 = proc_macro2::TokenStream::from(module);
     let items = input.items.iter();
     let _idents = input.get_idents();
     let variants = input.get_enum_idents();
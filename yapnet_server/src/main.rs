@@ -23,7 +23,8 @@ use axum::{
     routing::get,
     Router,
 };
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
+use yapnet_core::lua::LuaLimits;
 use tower_http::services::ServeDir;
 
 mod lua;
@@ -36,13 +37,76 @@ pub struct AppStateT {
     pub server_handle: server::ServerHandle,
 }
 
+/// Command line options
+/// Usage: yapnet <game.lua> [--save <dir>] [--resume | --overwrite] [--max-instructions <n>] [--max-memory <MiB>]
+pub struct Options {
+    /// The game script
+    pub script: PathBuf,
+    /// Where the game is saved, nothing is saved if missing
+    pub save_dir: Option<PathBuf>,
+    /// Continue the game saved in save_dir instead of starting a new one
+    pub resume: bool,
+    /// Start a new game in save_dir even if a game is saved there
+    pub overwrite: bool,
+    /// What the game script is allowed to use
+    pub limits: LuaLimits,
+}
+
+impl Options {
+    pub fn from_argv() -> Self {
+        let mut args = std::env::args().skip(1);
+        let mut script = None;
+        let mut save_dir = None;
+        let mut resume = false;
+        let mut overwrite = false;
+        let mut limits = LuaLimits::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--save" => save_dir = Some(args.next().expect("--save needs a directory").into()),
+                "--resume" => resume = true,
+                "--overwrite" => overwrite = true,
+                "--max-instructions" => {
+                    limits.instructions = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--max-instructions needs a number")
+                }
+                "--max-memory" => {
+                    let mib: usize = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .expect("--max-memory needs a number of MiB");
+                    limits.memory = mib * 1024 * 1024
+                }
+                _ => script = Some(arg.into()),
+            }
+        }
+
+        if resume && save_dir.is_none() {
+            panic!("--resume needs the directory given with --save");
+        }
+        if overwrite && (resume || save_dir.is_none()) {
+            panic!("--overwrite needs the directory given with --save, and cannot be used with --resume");
+        }
+
+        Self {
+            script: script.expect("No file argument given"),
+            save_dir,
+            resume,
+            overwrite,
+            limits,
+        }
+    }
+}
+
 /// Entry
 #[tokio::main]
 async fn main() {
     // TODO: Import tracing crate and figure out good logging strategy
     //tracing_subscriber::fmt::init();
 
-    let (server, handle) = server::Server::create().await;
+    let (server, handle) = server::Server::create(&Options::from_argv()).await;
 
     let state = std::sync::Arc::new(AppStateT {
         server_handle: handle,
@@ -13,18 +13,21 @@
 //   limitations under the License.
 
 use yapnet_core::lua::state_init;
-use async_recursion::async_recursion;
 use axum::extract::ws::{CloseFrame, Message as WsMessage, WebSocket};
 use yapnet_core::error::ClientError;
-use yapnet_core::protocol::ChatId;
+use yapnet_core::models::game::Player;
 use yapnet_core::state::{ResponseView, YapnetResponse};
 use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::time::Duration;
 use tokio::{
     select,
-    sync::mpsc::{channel, Receiver, Sender},
+    sync::mpsc::{channel, error::TrySendError, Receiver, Sender},
     task::JoinHandle,
+    time::{sleep_until, Instant},
 };
-use yapnet_core::lua::yapi::init_lua_from_argv;
+use yapnet_core::lua::yapi::load_lua;
+use crate::Options;
 use yapnet_core::prelude::Message;
 use yapnet_core::prelude::*;
 
@@ -33,6 +36,12 @@ pub struct ClientConnection {
     pub id: usize,
     pub to_client: Sender<ClientMessage>,
     client_handle: JoinHandle<()>,
+    /// Highest seq the client said it processed
+    pub acked: Option<u64>,
+    /// Seq of the last history message sent, the next one points back to it
+    sent: Option<u64>,
+    /// A message could not be sent, so the client needs a recap
+    lagging: bool,
 }
 
 type ClientMessage = String;
@@ -83,9 +92,37 @@ pub struct Server {
 }
 
 impl Server {
+    /// How long to sleep when nothing is scheduled
+    const IDLE_TICK: Duration = Duration::from_secs(60);
+
     /// Make the server and the handle
-    pub async fn create() -> (Self, ServerHandle) {
-        let state = state_init(init_lua_from_argv());
+    pub async fn create(opts: &Options) -> (Self, ServerHandle) {
+        println!("Loading the game script {}", opts.script.display());
+        let lua = load_lua(&opts.script, opts.limits).unwrap_or_else(|e| {
+            eprintln!("Cannot load {}: {}", opts.script.display(), e);
+            std::process::exit(1)
+        });
+        let mut state = state_init(lua).unwrap_or_else(|report| {
+            eprintln!("{}", report);
+            std::process::exit(1)
+        });
+        state.script = Some(opts.script.clone());
+        if let Some(dir) = &opts.save_dir {
+            let res = match opts.resume {
+                true => state.resume(dir),
+                false => state.start_journal(dir, opts.overwrite),
+            };
+            res.unwrap_or_else(|e| match e.kind() {
+                ErrorKind::AlreadyExists => {
+                    eprintln!(
+                        "A game is saved in {} already, continue it with --resume or replace it with --overwrite",
+                        dir.display()
+                    );
+                    std::process::exit(1)
+                }
+                _ => panic!("Cannot use the save in {}: {}", dir.display(), e),
+            });
+        }
 
         let (message_send, message_recv) = channel(128);
         let (add_clients_send, add_clients_recv) = channel(8);
@@ -114,7 +151,13 @@ impl Server {
     /// The task that manages websocket connection
     pub async fn run(mut self) {
         loop {
+            let wake_at = self.next_wakeup();
             select! {
+                _ = sleep_until(wake_at) => {
+                    let res = self.state.tick();
+                    // Nobody sent this, so there is no client to return to
+                    self.send_result(usize::MAX, res).await;
+                }
                 client_opt = self.add_clients.recv() => {
                     let comm = client_opt.unwrap();
                     let id = self.highest_id;
@@ -127,11 +170,8 @@ impl Server {
                     let close = client_opt.unwrap();
                     self.clients.remove(&close.id);
                     if let Some(uname)  = self.users_connections.remove(&close.id) {
-                        // Bypassing the borrow checker, since we know that the reference in res in
-                        // immutable.
-                        let ptr = std::ptr::from_ref(&self);
                         let res = self.state.player_leave(&uname).unwrap();
-                        unsafe { (*ptr).send_result(close.id, res).await };
+                        self.send_result(close.id, res).await;
                     }
                     self.display_clients();
                 }
@@ -139,20 +179,25 @@ impl Server {
                     let msg = msg_opt.unwrap();
                     let cid = msg.seq;
                     println!("Message recieved!: {:?}", &msg);
-                    // Bypassing the borrow checker, since we know that the reference in res in
-                    // immutable.
-                    let ptr = std::ptr::from_ref(&self);
                     let res = self.handle_message(msg);
-                    unsafe { 
-                        let refr: &Server = &(*ptr); 
-                        refr.send_result(cid as usize, res).await;
-                        refr.state.print_messages();
-                    }
+                    self.send_result(cid as usize, res).await;
+                    self.state.print_messages();
                 }
             }
         }
     }
 
+    /// When the state next needs to tick (votes ending, timers firing)
+    fn next_wakeup(&self) -> Instant {
+        match self.state.next_deadline() {
+            Some(deadline) => {
+                let wait = (deadline - chrono::Utc::now()).to_std().unwrap_or(Duration::ZERO);
+                Instant::now() + wait
+            }
+            None => Instant::now() + Self::IDLE_TICK,
+        }
+    }
+
     fn display_clients(&self) {
         print!("Client_Connections: [");
         for (i, _) in self.clients.iter() {
@@ -164,9 +209,7 @@ impl Server {
         }
     }
 
-    pub fn handle_message<'s,'v>(&'s mut self, m: Message) -> ResponseView<'v> 
-        where 's:'v 
-    {
+    pub fn handle_message(&mut self, m: Message) -> ResponseView {
         let cid = m.seq;
         match m.data {
             MessageData::BodyHello(Hello { username }) => {  
@@ -181,21 +224,50 @@ impl Server {
                     },
                 }
             },
-            MessageData::BodyBack(Back { token }) =>
-                match self.state.reauth_user(token) {
+            MessageData::BodyBack(Back { token, last_seq, last_rewind }) =>
+                match self.state.reauth_user(token, last_seq, last_rewind) {
                     Ok((username,frame)) => {
                         self.users_connections
                             .insert(cid as usize, username);
+                        if let Some(client) = self.clients.get_mut(&(cid as usize)) {
+                            client.sent = last_seq;
+                        }
                         frame
                     },
                     Err(e) => {
                         ResponseView::from_message_return(e)
                     },
                 }
+            MessageData::BodyAck(Ack { seq }) => self.handle_ack(cid as usize, seq),
+            MessageData::BodyResync(Resync { last_seq, .. }) => {
+                // The recap that follows continues from here
+                if let Some(client) = self.clients.get_mut(&(cid as usize)) {
+                    client.sent = last_seq;
+                    client.lagging = false;
+                }
+                self.auth_handle_message(m)
+            }
             _ => self.auth_handle_message(m),
         }
     }
-    pub fn auth_handle_message(&mut self, m: Message) -> ResponseView<'_>{
+
+    /// Keeps track of what the client has, a client that missed messages gets the recap from there
+    fn handle_ack(&mut self, cid: usize, seq: u64) -> ResponseView {
+        let Some(client) = self.clients.get_mut(&cid) else {
+            return ResponseView::empty();
+        };
+        client.acked = client.acked.max(Some(seq));
+        match self.users_connections.get(&cid) {
+            Some(username) if client.lagging => {
+                println!("[{}] missed messages, sending the recap after {}", username, seq);
+                client.lagging = false;
+                client.sent = client.acked;
+                self.state.resync(username, client.acked, None)
+            }
+            _ => ResponseView::empty(),
+        }
+    }
+    pub fn auth_handle_message(&mut self, m: Message) -> ResponseView {
         if let Some(username) = self.users_connections.get(&(m.seq as usize)) {
             self.state.handle_message_serveir(username, m)
         } else {
@@ -203,89 +275,65 @@ impl Server {
         }
     }
 
-    #[async_recursion(?Send)]
-    async fn try_serialize_send(&self, client: &ClientConnection, m: &Message) {
-        let x = serde_json::to_string(&m);
-        if let Ok(msg) = x {
-            match client.to_client.send(msg).await {
-                Ok(()) => (),
-                Err(err) => eprintln!("[Send error] {:?}", err),
-                // TODO: Handle this error by disconnecting the player
-            }
-        } else if let Err(err) = x {
-            match client.to_client.send(serde_json::to_string(&(YnError::new("SerializationError","The message you sent was malformed",format!("{{ \"SerdeError\":\"{}\"}}",err)).into_message())).unwrap()).await {
-                Ok(_) => {},
-                Err(e) => {eprintln!("Send error: {}", e)},
-            };
-        }
+    /// Clients that get to see the message, clients that did not log in only see global messages
+    fn viewing_clients(&self, m: &Message) -> Vec<usize> {
+        self.clients
+            .keys()
+            .filter(|cid| match self.users_connections.get(cid) {
+                Some(username) => self.state.user_can_view(m, username),
+                None => m.data.to_inner_ref().is_global(),
+            })
+            .copied()
+            .collect()
     }
 
-    async fn try_serialize_send_all_ex<'a,'s, T: Iterator<Item = (&'a usize, &'a ClientConnection)>>(&'s self, m: &Message,cid_ex: usize ,  iter: T) {
-        for (cid2, client) in iter {
-            if cid_ex == *cid2 {
-                continue;
-            }
-            self.try_serialize_send(client, m).await;
-            // TODO: Handle error and see what we can do about the clone
-        }
-    }
-    async fn try_serialize_send_all<'a,'s, T: Iterator<Item = (&'a usize, &'a ClientConnection)>>(&'s self, m: &Message, iter: T) {
-        for (_, client) in iter {
-            self.try_serialize_send(client, m).await;
-            // TODO: Handle error and see what we can do about the clone
-        }
-    }
-
-    // TODO: rewrite as iter?
-    fn all_participating_clients<'s,'i>(&'s self, chatid: &ChatId) -> Vec<(&usize,&ClientConnection)> 
-    where 
-      's: 'i
-    {
-           match self.state.chats.get(chatid) {
-                None => vec![],
-                Some(chat) =>  {
-                   self.users_connections.iter().filter_map(|(k,v)| {
-                        match chat.can_read(self.state.users.get(v).expect("users_connections should be a subset of state.users ")) {
-                            true => self.clients.get(k).map(|c| (k, c)),
-                            false => None,
-                        }
-                    }).collect() 
-                } 
-           } 
+    /// Clients of the users that are admins
+    fn admin_clients(&self) -> Vec<usize> {
+        self.users_connections
+            .iter()
+            .filter(|(_, username)| self.state.game.players.get(*username).is_some_and(Player::is_admin))
+            .map(|(cid, _)| *cid)
+            .collect()
     }
 
-    async fn send_result<'a>(&self, cid: usize, rv: ResponseView<'a>) {
+    /// Live messages never wait for a client, the ones returned to it do, so a recap is never cut short
+    async fn send_result(&mut self, cid: usize, rv: ResponseView) {
         for (resp,m) in rv.iter() {
-            match resp {
+            let targets = match resp {
                 YapnetResponse::Return(_) => {
-                    if let Some(client) = self.clients.get(&cid) {
-                        self.try_serialize_send(client, m).await;
+                    if let Some(client) = self.clients.get_mut(&cid) {
+                        client.send_return(m).await;
                     }
+                    continue;
                 }
-                YapnetResponse::BroadcastExclusive(_, chat) => {
-                    if chat == "system:all" {
-                        self.try_serialize_send_all_ex(m , cid, self.clients.iter()).await;
-                    } else {
-                        self.try_serialize_send_all_ex(m, cid, self.all_participating_clients(chat).into_iter()).await; 
+                YapnetResponse::Admins(_) => {
+                    for id in self.admin_clients() {
+                        if let Some(client) = self.clients.get_mut(&id) {
+                            client.send_return(m).await;
+                        }
                     }
+                    continue;
                 }
-                YapnetResponse::Broadcast(_, chat) => {
-                    if chat == "system:all" {
-                        self.try_serialize_send_all(m ,self.clients.iter()).await;
-                    } else {
-                        self.try_serialize_send_all(m, self.all_participating_clients(chat).into_iter()).await; 
-                    }
+                YapnetResponse::BroadcastExclusive(..) => self.viewing_clients(m).into_iter().filter(|id| *id != cid).collect(),
+                YapnetResponse::Broadcast(..) => self.viewing_clients(m),
+                YapnetResponse::None => vec![],
+            };
+            for id in targets {
+                if let Some(client) = self.clients.get_mut(&id) {
+                    client.send_live(m);
                 }
-                YapnetResponse::None => {} 
             }
         }
     }
 }
 
 impl ClientConnection {
+    /// Messages that can wait for a client before it counts as lagging
+    const BUFFER: usize = 64;
+
     /// Creates a client connection and spawns the task
     pub fn create_client(id: usize, ws: WebSocket, handle: &ServerHandle) -> Self {
-        let (to_client, from_server) = channel(8);
+        let (to_client, from_server) = channel(Self::BUFFER);
 
         let client = Client {
             cid: id,
@@ -301,6 +349,50 @@ impl ClientConnection {
             id,
             to_client,
             client_handle,
+            acked: None,
+            sent: None,
+            lagging: false,
+        }
+    }
+
+    fn encode(m: &Message) -> ClientMessage {
+        serde_json::to_string(&m).unwrap_or_else(|err| {
+            let error = YnError::new("SerializationError","The message you sent was malformed",format!("{{ \"SerdeError\":\"{}\"}}",err));
+            serde_json::to_string(&error.into_message()).expect("Errors always serialize")
+        })
+    }
+
+    /// Sends without waiting, a client that cannot keep up misses the message and is marked as lagging
+    fn try_send(&mut self, m: &Message) {
+        match self.to_client.try_send(Self::encode(m)) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                eprintln!("[Send error] Client {} is not keeping up", self.id);
+                self.lagging = true;
+            }
+            // The connection is closing, the server will hear about it
+            Err(TrySendError::Closed(_)) => (),
+        }
+    }
+
+    /// Sends a message from the history, linking it to the one sent before
+    fn send_live(&mut self, m: &Message) {
+        let mut live = m.clone();
+        live.prev = self.sent.replace(m.seq);
+        self.try_send(&live);
+    }
+
+    /// Sends a message only meant for this client, waiting for room instead of dropping it.
+    /// A recap moves the client ahead once it is sent.
+    async fn send_return(&mut self, m: &Message) {
+        if self.to_client.send(Self::encode(m)).await.is_err() {
+            // The connection is closing, the server will hear about it
+            return;
+        }
+        if let MessageData::BodyRecapTail(RecapTail { msgs, .. }) = &m.data {
+            if let Some(seq) = msgs.last().and_then(|v| v["seq"].as_u64()) {
+                self.sent = Some(seq);
+            }
         }
     }
 }
@@ -325,6 +417,7 @@ impl Client {
                                             Err(err) => {
                                                 let msg = Message {
                                                     seq: 0,
+                                                    prev: None,
                                                     data: YnError{
                                                         kind: "InvalidMessage".to_string(),
                                                         info: format!("{:?}", err),